@@ -44,7 +44,7 @@ mod unix_example {
         }
     }
 
-    async fn post_handler(req: AsyncRequest) -> Result<Response, String> {
+    async fn post_handler(mut req: AsyncRequest) -> Result<Response, String> {
         let mongo = req.deps.get::<Client>().unwrap();
         let my_coll: Collection<Restaurant> = mongo.database("gym-log").collection("restaurants");
         let buf = req.body().await.unwrap();
@@ -73,7 +73,11 @@ mod unix_example {
 
         AsyncHttpServer::builder()
             .with_port(8090)
-            .with_handlers(HashSet::from([AsyncHandler::new("GET", "/get/:name", get_handler), AsyncHandler::new("POST", "/post", post_handler)]))
+            // the handlers unwrap the client, with it declared a server without one does not even build
+            .with_handlers(HashSet::from([
+                AsyncHandler::new("GET", "/get/:name", get_handler).with_required_dep::<Client>(),
+                AsyncHandler::new("POST", "/post", post_handler).with_required_dep::<Client>(),
+            ]))
             .with_dep(client)
             .build()
             .start_blocking()
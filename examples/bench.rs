@@ -0,0 +1,51 @@
+// Raw server overhead: a handler that does nothing, driven over kept alive, pipelined connections.
+// cargo run --release --example bench -- [connections] [requests per connection]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::thread;
+use std::time::Instant;
+
+use nvo_servers::http::async_handler::AsyncHandler;
+use nvo_servers::http::AsyncRequest;
+
+use common::TestServer;
+
+fn main() {
+    async fn noop_handler(_: AsyncRequest) -> &'static str {
+        ""
+    }
+
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse::<usize>().expect("Arguments have to be numbers"));
+    let connections = args.next().unwrap_or(4);
+    let requests = args.next().unwrap_or(10_000);
+
+    let server = TestServer::start(HashSet::from([AsyncHandler::new("GET", "/noop", noop_handler)]));
+    let request = b"GET /noop HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    let started = Instant::now();
+    let clients = (0..connections)
+        .map(|_| {
+            let mut client = server.raw_client();
+            thread::spawn(move || {
+                // sent from its own thread, so the requests queue up on the connection while the responses get read
+                let mut writer = client.writer();
+                let sender = thread::spawn(move || writer.write_all(&request.repeat(requests)).expect("Could not send the requests"));
+                for _ in 0..requests {
+                    assert_eq!(client.read_response().status_code(), 200);
+                }
+                sender.join().unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    clients.into_iter().for_each(|client| client.join().unwrap());
+    let elapsed = started.elapsed();
+
+    let total = connections * requests;
+    println!(
+        "{total} requests over {connections} connections in {elapsed:?}, {per_sec:.0} requests/sec",
+        per_sec = total as f64 / elapsed.as_secs_f64()
+    );
+}
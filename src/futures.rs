@@ -1,4 +1,5 @@
 pub mod catch_unwind;
 pub mod result_handle;
+pub mod sync;
 pub mod worker;
 pub mod workers;
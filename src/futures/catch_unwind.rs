@@ -1,10 +1,18 @@
 use std::{
     any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
     future::Future,
-    panic::{catch_unwind, AssertUnwindSafe},
+    panic::{self, catch_unwind, AssertUnwindSafe},
     pin::Pin,
+    sync::Once,
 };
 
+thread_local! {
+    // where the last panic on this thread happened, see install_backtrace_hook
+    static LAST_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
 pub struct CatchUnwind<F> {
     future: Pin<Box<F>>,
 }
@@ -36,6 +44,33 @@ where
     }
 }
 
+pub fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        Some(msg)
+    } else {
+        payload.downcast_ref::<String>().map(String::as_str)
+    }
+}
+
+// From now on every panic records its backtrace for take_backtrace, whatever hook was there before still runs.
+// Installed at most once per process, capturing costs a stack walk per panic.
+pub fn install_backtrace_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(Backtrace::force_capture()));
+            previous(info)
+        }));
+    });
+}
+
+// The backtrace of the last panic on this thread, once. The panic gets caught on the thread it happened on, so right after
+// catching it this is its backtrace. None without install_backtrace_hook.
+pub fn take_backtrace() -> Option<Backtrace> {
+    LAST_BACKTRACE.with(|last| last.borrow_mut().take())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -1,4 +1,7 @@
-use std::sync::{Condvar, Mutex};
+use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
 
 use log::debug;
 
@@ -38,11 +41,26 @@ impl<T> ResultHandle<T> {
         self.is_set.notify_one();
         value
     }
+
+    // Like get, but gives up after `timeout`. A value set later is still there for the next get.
+    pub fn get_timeout(&self, timeout: Duration) -> Option<T> {
+        let data_lock = self.value.lock().expect("poisoned lock");
+        let (mut data_lock, _) = self.is_set.wait_timeout_while(data_lock, timeout, |value| value.is_none()).expect("sync broken");
+        let value = data_lock.take();
+        if value.is_some() {
+            self.is_set.notify_one();
+        }
+        value
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{sync::Arc, thread};
+    use std::{
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
 
     use crate::utils;
 
@@ -71,4 +89,26 @@ mod tests {
         assert_eq!(clone_under_test.get(), number);
         t.join().unwrap();
     }
+
+    #[test]
+    fn get_timeout_returns_a_value_set_in_time() {
+        let under_test: Arc<ResultHandle<u32>> = Arc::new(ResultHandle::new());
+        let clone_under_test = under_test.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            clone_under_test.set(42)
+        });
+
+        assert_eq!(under_test.get_timeout(Duration::from_secs(5)), Some(42));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn get_timeout_gives_up_on_a_never_set_handle() {
+        let under_test: ResultHandle<u32> = ResultHandle::new();
+
+        let start = Instant::now();
+        assert_eq!(under_test.get_timeout(Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
 }
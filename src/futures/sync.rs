@@ -0,0 +1,287 @@
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::{Mutex as StdMutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+
+// Waiting tasks are parked (Poll::Pending) instead of blocking the worker thread, a released permit wakes them up again
+pub struct Semaphore {
+    state: StdMutex<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    permits: usize,
+    waiters: Vec<Waker>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            state: StdMutex::new(SemaphoreState { permits, waiters: Vec::new() }),
+        }
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire { semaphore: self }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).permits
+    }
+
+    // Every waiter gets woken and races for the permit, one that lost (or got dropped meanwhile) cannot strand the rest
+    fn release(&self) {
+        let waiters = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.permits += 1;
+            std::mem::take(&mut state.waiters)
+        };
+        waiters.into_iter().for_each(Waker::wake);
+    }
+}
+
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.semaphore.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.permits > 0 {
+            state.permits -= 1;
+            return Poll::Ready(SemaphorePermit { semaphore: self.semaphore });
+        }
+        state.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// Gives the permit back when dropped
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+// One way switch, tasks waiting for it stay parked until it gets opened and every later wait finishes right away
+pub struct Latch {
+    state: StdMutex<LatchState>,
+}
+
+struct LatchState {
+    open: bool,
+    waiters: Vec<Waker>,
+}
+
+impl Latch {
+    pub fn new() -> Latch {
+        Latch {
+            state: StdMutex::new(LatchState { open: false, waiters: Vec::new() }),
+        }
+    }
+
+    pub fn open(&self) {
+        let waiters = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.open = true;
+            std::mem::take(&mut state.waiters)
+        };
+        waiters.into_iter().for_each(Waker::wake);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).open
+    }
+
+    pub fn wait(&self) -> LatchWait<'_> {
+        LatchWait { latch: self }
+    }
+}
+
+impl Default for Latch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct LatchWait<'a> {
+    latch: &'a Latch,
+}
+
+impl Future for LatchWait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.latch.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.open {
+            return Poll::Ready(());
+        }
+        state.waiters.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+// Can be held across an .await, unlike std::sync::Mutex a task waiting for it leaves the worker free for other tasks
+pub struct Mutex<T> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// Access to `value` only ever goes through a guard, and the single permit makes sure there is at most one guard
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Mutex<T> {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        let permit = self.semaphore.acquire().await;
+        MutexGuard {
+            mutex: self,
+            _permit: permit,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    _permit: SemaphorePermit<'a>,
+    // shared guards hand out &T, so the guard is only Sync when T is
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    use crate::futures::workers::Workers;
+
+    use super::{Latch, Mutex, Semaphore};
+
+    // Goes back to the end of the queue once, so other tasks on the same worker get a turn
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn contending_tasks_share_a_single_worker() {
+        let workers = Workers::new(1);
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+
+        let holder = mutex.clone();
+        let first = workers
+            .queue_with_result(async move {
+                let mut guard = holder.lock().await;
+                guard.push("first locked");
+                // a std mutex would block the only worker once the second task tries to lock
+                YieldNow(false).await;
+                guard.push("first unlocking");
+            })
+            .unwrap();
+        let waiter = mutex.clone();
+        let second = workers
+            .queue_with_result(async move {
+                waiter.lock().await.push("second locked");
+            })
+            .unwrap();
+        first.get();
+        second.get();
+        workers.poison_all();
+
+        assert_eq!(Arc::try_unwrap(mutex).ok().unwrap().into_inner(), vec!["first locked", "first unlocking", "second locked"]);
+    }
+
+    #[test]
+    fn semaphore_caps_concurrent_holders() {
+        let workers = Workers::new(1);
+        let semaphore = Arc::new(Semaphore::new(2));
+        let holding = Arc::new(AtomicUsize::new(0));
+        let max_holding = Arc::new(AtomicUsize::new(0));
+
+        let results = (0..4)
+            .map(|_| {
+                let (semaphore, holding, max_holding) = (semaphore.clone(), holding.clone(), max_holding.clone());
+                workers
+                    .queue_with_result(async move {
+                        let _permit = semaphore.acquire().await;
+                        let now_holding = holding.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_holding.fetch_max(now_holding, Ordering::SeqCst);
+                        YieldNow(false).await;
+                        holding.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        results.into_iter().for_each(|result| result.get());
+        workers.poison_all();
+
+        assert_eq!(max_holding.load(Ordering::SeqCst), 2);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn latch_wakes_every_waiter_once_opened() {
+        let workers = Workers::new(2);
+        let latch = Arc::new(Latch::new());
+
+        let waiters = (0..3)
+            .map(|_| {
+                let latch = latch.clone();
+                workers.queue_with_result(async move { latch.wait().await }).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(!latch.is_open());
+        latch.open();
+        waiters.into_iter().for_each(|waiter| waiter.get());
+        // already open, nothing to wait for
+        workers.queue_with_result(async move { latch.wait().await }).unwrap().get();
+        workers.poison_all();
+    }
+}
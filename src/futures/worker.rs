@@ -1,10 +1,13 @@
+use crate::futures::catch_unwind::panic_message;
 use log::{debug, error, info};
 use std::future::Future;
 use std::ops::Deref;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex};
-use std::task::{Context, Wake, Waker};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -26,23 +29,33 @@ pub struct Worker {
 }
 
 impl Worker {
-    pub(crate) fn new(name: String, recv: Arc<Mutex<Receiver<Arc<ChannelMsg>>>>) -> Worker {
+    // The thread is named after the worker. `exited` goes up once the thread is about to finish, however it got there.
+    pub(crate) fn new(name: String, stack_size: Option<usize>, recv: Arc<Mutex<Receiver<Arc<ChannelMsg>>>>, exited: Arc<AtomicUsize>) -> Worker {
         let worker_name = name.clone();
-        let thread_handle = thread::spawn(move || loop {
-            match recv.lock().expect("poisoned lock").recv() {
+        let builder = thread::Builder::new().name(name.clone());
+        let builder = match stack_size {
+            Some(stack_size) => builder.stack_size(stack_size),
+            None => builder,
+        };
+        let thread_handle = builder
+            .spawn(move || {
+                let _exit_guard = ExitGuard(exited);
+                Self::run(&worker_name, &recv)
+            })
+            .unwrap_or_else(|e| panic!("Failed to spawn worker thread {name}, reason: {e}"));
+
+        Worker { name, thread_handle }
+    }
+
+    fn run(worker_name: &str, recv: &Mutex<Receiver<Arc<ChannelMsg>>>) {
+        loop {
+            // do not hold the receiver while working, a panic would poison it for the whole pool
+            let msg = recv.lock().unwrap_or_else(PoisonError::into_inner).recv();
+            match msg {
                 Ok(task_ptr) => {
                     debug!("Executing job. Worker name: {worker_name}");
                     match task_ptr.deref() {
-                        ChannelMsg::Task(task) => {
-                            let mut future_mutex = task.future.lock().expect("poisoned lock");
-                            if let Some(mut future) = future_mutex.take() {
-                                let waker = Waker::from(task_ptr.clone());
-                                let context = &mut Context::from_waker(&waker);
-                                if future.as_mut().poll(context).is_pending() {
-                                    *future_mutex = Some(future)
-                                }
-                            }
-                        }
+                        ChannelMsg::Task(task) => Self::process_task(worker_name, task, task_ptr.clone()),
 
                         ChannelMsg::Shutdown => break,
                     }
@@ -52,9 +65,32 @@ impl Worker {
                     break;
                 }
             }
-        });
+        }
+    }
 
-        Worker { name, thread_handle }
+    // A panicking task must not take the worker thread down with it
+    fn process_task(worker_name: &str, task: &Task, task_ptr: Arc<ChannelMsg>) {
+        let mut future_mutex = task.future.lock().expect("poisoned lock");
+        if let Some(mut future) = future_mutex.take() {
+            let waker = Waker::from(task_ptr);
+            let context = &mut Context::from_waker(&waker);
+            match catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(context))) {
+                Ok(Poll::Pending) => *future_mutex = Some(future),
+                Ok(Poll::Ready(())) => (),
+                Err(e) => error!(
+                    "Task panicked. Worker name: {worker_name}, reason: {reason}",
+                    reason = panic_message(e.as_ref()).unwrap_or("cannot interpret panic")
+                ),
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread_handle.is_finished()
     }
 
     pub fn gracefully_shutdown(self, sender: Sender<Arc<ChannelMsg>>) {
@@ -62,6 +98,19 @@ impl Worker {
         sender.send(Arc::new(ChannelMsg::Shutdown)).unwrap();
         self.thread_handle.join().unwrap();
     }
+
+    pub fn join(self) {
+        info!("Joining worker {}", self.name);
+        self.thread_handle.join().unwrap();
+    }
+}
+
+struct ExitGuard(Arc<AtomicUsize>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Release);
+    }
 }
 
 impl Wake for ChannelMsg {
@@ -87,7 +136,7 @@ mod tests {
     fn worker_can_process_work() {
         static IS_MODIFIED: AtomicBool = AtomicBool::new(false);
         let (sender, recv) = channel::<Arc<ChannelMsg>>();
-        let worker = Worker::new("a-worker".to_string(), Arc::new(Mutex::new(recv)));
+        let worker = Worker::new("a-worker".to_string(), None, Arc::new(Mutex::new(recv)), Arc::default());
         let boxed_future = Box::pin(async {
             IS_MODIFIED.swap(true, Relaxed);
         });
@@ -103,4 +152,29 @@ mod tests {
 
         worker.gracefully_shutdown(sender)
     }
+
+    #[test]
+    fn worker_survives_a_panicking_task() {
+        static IS_MODIFIED: AtomicBool = AtomicBool::new(false);
+        let (sender, recv) = channel::<Arc<ChannelMsg>>();
+        let worker = Worker::new("a-worker".to_string(), None, Arc::new(Mutex::new(recv)), Arc::default());
+        let panicking_task = ChannelMsg::Task(Task {
+            future: Mutex::new(Some(Box::pin(async { panic!("panic") }))),
+            sender: sender.clone(),
+        });
+        let task = ChannelMsg::Task(Task {
+            future: Mutex::new(Some(Box::pin(async {
+                IS_MODIFIED.swap(true, Relaxed);
+            }))),
+            sender: sender.clone(),
+        });
+        sender.send(Arc::new(panicking_task)).unwrap();
+        sender.send(Arc::new(task)).unwrap();
+
+        while !IS_MODIFIED.load(Relaxed) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        worker.gracefully_shutdown(sender)
+    }
 }
@@ -1,16 +1,46 @@
 use std::future::Future;
-use std::sync::mpsc::{channel, SendError, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::futures::result_handle::ResultHandle;
-use log::debug;
+use log::{debug, error, warn};
 
 use crate::futures::worker::{ChannelMsg, Worker};
 
 use super::worker::Task;
 
 pub struct Workers {
-    workers: Vec<Worker>,
+    workers: Mutex<Vec<Worker>>,
+    config: WorkersConfig,
+    receiver: Arc<Mutex<Receiver<Arc<ChannelMsg>>>>,
+    // worker threads that exited and have not been replaced yet, lets `queue` skip the workers lock while all of them are alive
+    exited: Arc<AtomicUsize>,
+    handle: WorkersHandle,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorkersConfig {
+    // worker threads are named `{name_prefix}{n}`, shows up in profilers, debuggers and panic messages
+    pub name_prefix: String,
+    // None keeps the platform default, handlers recursing deeply may need more
+    pub stack_size: Option<usize>,
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        WorkersConfig {
+            name_prefix: "worker-".to_string(),
+            stack_size: None,
+        }
+    }
+}
+
+// Cheap to clone, queues onto the pool it came from without owning it. Shutting the pool down stays with `Workers`.
+#[derive(Clone)]
+pub struct WorkersHandle {
     sender: Sender<Arc<ChannelMsg>>,
 }
 
@@ -18,14 +48,97 @@ type ShareableResultHandle<T> = Arc<ResultHandle<T>>;
 
 impl Workers {
     pub fn new(size: usize) -> Workers {
+        Workers::with_config(size, WorkersConfig::default())
+    }
+
+    pub fn with_config(size: usize, config: WorkersConfig) -> Workers {
         let (sender, receiver) = channel::<Arc<ChannelMsg>>();
         let receiver = Arc::new(Mutex::new(receiver));
-        let _workers = (0..size).map(|x| Worker::new(x.to_string(), receiver.clone())).collect();
+        let exited = Arc::new(AtomicUsize::new(0));
+        let _workers = (0..size)
+            .map(|x| Worker::new(format!("{prefix}{x}", prefix = config.name_prefix), config.stack_size, receiver.clone(), exited.clone()))
+            .collect();
 
         debug!("Starting {size} workers (threads).");
-        Workers { workers: _workers, sender }
+        Workers {
+            workers: Mutex::new(_workers),
+            config,
+            receiver,
+            exited,
+            handle: WorkersHandle { sender },
+        }
+    }
+
+    pub fn handle(&self) -> WorkersHandle {
+        self.handle.clone()
+    }
+
+    // Dead worker threads get replaced before new work is handed out, so the pool never silently shrinks.
+    // Only an atomic load while every worker is alive, queueing from many threads does not serialize on the workers lock.
+    fn revive_dead_workers(&self) {
+        if self.exited.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let mut workers = self.workers.lock().expect("poisoned lock");
+        // a worker that has just counted itself may not be finished yet, it stays counted and gets picked up by a later call
+        workers.iter_mut().filter(|w| w.is_finished()).for_each(|w| {
+            warn!("Worker {name} died, starting a replacement.", name = w.name());
+            *w = Worker::new(w.name().to_string(), self.config.stack_size, self.receiver.clone(), self.exited.clone());
+            self.exited.fetch_sub(1, Ordering::AcqRel);
+        });
     }
 
+    pub fn queue(&self, future: impl Future<Output = ()> + 'static + Send) -> Result<(), SendError<Arc<ChannelMsg>>> {
+        self.revive_dead_workers();
+        self.handle.queue(future)
+    }
+
+    pub fn queue_with_result<F>(&self, future: F) -> Result<ShareableResultHandle<F::Output>, SendError<Arc<ChannelMsg>>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send,
+    {
+        self.revive_dead_workers();
+        self.handle.queue_with_result(future)
+    }
+
+    // Every worker shares the channel, so any of them may pick up any Shutdown. All of them get sent before joining anything.
+    pub fn poison_all(self) {
+        let workers = self.workers.into_inner().expect("poisoned lock");
+        workers.iter().for_each(|_| {
+            self.handle
+                .sender
+                .send(Arc::new(ChannelMsg::Shutdown))
+                .unwrap_or_else(|e| error!("Failed to send shutdown to a worker: {e}"))
+        });
+        workers.into_iter().for_each(Worker::join)
+    }
+
+    // Workers still busy when the timeout elapses are detached, returns how many tasks were abandoned that way
+    pub fn poison_all_timeout(self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let workers = self.workers.into_inner().expect("poisoned lock");
+        workers.iter().for_each(|_| {
+            self.handle
+                .sender
+                .send(Arc::new(ChannelMsg::Shutdown))
+                .unwrap_or_else(|e| error!("Failed to send shutdown to a worker: {e}"))
+        });
+
+        while Instant::now() < deadline && !workers.iter().all(Worker::is_finished) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let (finished, stuck): (Vec<Worker>, Vec<Worker>) = workers.into_iter().partition(Worker::is_finished);
+        finished.into_iter().for_each(Worker::join);
+        if !stuck.is_empty() {
+            warn!("Shutdown timed out after {timeout:?}, abandoning {abandoned} task(s) still running.", abandoned = stuck.len());
+        }
+        stuck.len()
+    }
+}
+
+impl WorkersHandle {
     pub fn queue(&self, future: impl Future<Output = ()> + 'static + Send) -> Result<(), SendError<Arc<ChannelMsg>>> {
         let task: Task = Task {
             future: Mutex::new(Some(Box::pin(future))),
@@ -55,16 +168,13 @@ impl Workers {
             Err(z) => Err(z),
         }
     }
-
-    pub fn poison_all(self) {
-        self.workers.into_iter().for_each(|w| w.gracefully_shutdown(self.sender.clone()))
-    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::hint::spin_loop;
-    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::atomic::AtomicBool;
     use std::thread::sleep;
     use std::time::Duration;
 
@@ -112,4 +222,115 @@ mod tests {
 
         workers.poison_all()
     }
+
+    #[test]
+    fn poison_all_timeout_returns_despite_a_stuck_task() {
+        let workers = Workers::new(2);
+        workers
+            .queue(async {
+                loop {
+                    sleep(Duration::from_millis(10));
+                }
+            })
+            .unwrap();
+
+        let start = Instant::now();
+        let abandoned = workers.poison_all_timeout(Duration::from_millis(100));
+
+        assert_eq!(abandoned, 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn workers_replace_a_dead_worker() {
+        static IS_MODIFIED: AtomicBool = AtomicBool::new(false);
+        let workers = Workers::new(1);
+        workers.handle.sender.send(Arc::new(ChannelMsg::Shutdown)).unwrap();
+        while !workers.workers.lock().unwrap().iter().all(|w| w.is_finished()) {
+            sleep(Duration::from_millis(1));
+        }
+
+        workers
+            .queue(async {
+                IS_MODIFIED.swap(true, Ordering::SeqCst);
+            })
+            .unwrap();
+
+        while !IS_MODIFIED.load(Ordering::SeqCst) {
+            sleep(Duration::from_millis(1));
+        }
+
+        workers.poison_all();
+    }
+
+    #[test]
+    fn handles_queue_from_several_threads_onto_the_shared_pool() {
+        let workers = Workers::new(2);
+
+        let results = (0..2)
+            .map(|i| {
+                let handle = workers.handle();
+                thread::spawn(move || handle.queue_with_result(async move { i * 10 }).unwrap().get())
+            })
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<i32>>();
+
+        assert_eq!(results, vec![0, 10]);
+        workers.poison_all();
+    }
+
+    #[test]
+    fn worker_threads_carry_the_name_prefix() {
+        let workers = Workers::with_config(
+            2,
+            WorkersConfig {
+                name_prefix: "api-worker-".to_string(),
+                stack_size: Some(4 * 1024 * 1024),
+            },
+        );
+
+        let names = (0..8)
+            .map(|_| workers.queue_with_result(async { thread::current().name().map(str::to_string) }).unwrap())
+            .map(|result| result.get().unwrap())
+            .collect::<Vec<String>>();
+        workers.poison_all();
+
+        assert!(names.iter().all(|name| name == "api-worker-0" || name == "api-worker-1"), "{names:?}");
+    }
+
+    #[test]
+    fn queueing_from_many_threads_spreads_work_over_all_workers() {
+        let workers = Arc::new(Workers::new(4));
+
+        let ran_on = (0..8)
+            .map(|_| {
+                let workers = workers.clone();
+                thread::spawn(move || {
+                    (0..50)
+                        .map(|_| {
+                            workers
+                                .queue_with_result(async {
+                                    // blocks on purpose, so queued tasks have to be picked up by the other workers
+                                    sleep(Duration::from_millis(1));
+                                    thread::current().id()
+                                })
+                                .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .fold(HashMap::new(), |mut ran_on, result| {
+                *ran_on.entry(result.get()).or_insert(0) += 1;
+                ran_on
+            });
+
+        assert_eq!(ran_on.values().sum::<i32>(), 400);
+        assert_eq!(ran_on.len(), 4);
+        // a fair share would be 100 each
+        assert!(ran_on.values().all(|&count| count >= 25), "{ran_on:?}");
+        Arc::try_unwrap(workers).ok().unwrap().poison_all();
+    }
 }
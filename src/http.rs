@@ -1,29 +1,60 @@
 use core::fmt;
 use std::{
     collections::HashMap,
+    fs::{self, File},
     io::{self, Read, Write},
+    mem,
     net::TcpStream,
-    sync::{Arc, Mutex},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use async_handler::AsyncHandler;
+use body_stream::{BodyFraming, BodyStream};
 use handler::Handler;
+use headers::Headers;
+use http_status::{HttpStatus, StatusCode};
+use limits::Limits;
 use log::debug;
+use parse_error::ParseError;
+use response::{Response, ResponseStream};
+use server_config::ServerConfig;
+use uri::Uri;
 
 use crate::typemap::DepsMap;
 
-#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+#[cfg(all(any(target_os = "freebsd", target_os = "macos"), not(feature = "poll_reactor")))]
 pub mod async_bsd_http_server;
 pub mod async_http_server;
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", not(feature = "poll_reactor")))]
 pub mod async_linux_http_server;
+#[cfg(all(unix, any(feature = "poll_reactor", not(any(target_os = "linux", target_os = "freebsd", target_os = "macos")))))]
+pub mod async_poll_http_server;
 
 pub mod async_handler;
 pub mod blocking_http_server;
+pub mod body_stream;
+pub mod compiled_path;
+mod content_encoding;
 pub mod handler;
+pub mod headers;
 mod helpers;
+pub mod http_date;
 pub mod http_status;
+pub mod json_body;
+pub mod limits;
+pub mod middleware;
+pub mod parse_error;
 pub mod response;
+pub mod response_builder;
+pub mod server_config;
+#[cfg(unix)]
+mod shutdown_signal;
+pub mod uri;
 
 pub trait ConnStream: Read + Write + Peek + TryClone + Send + Sync {}
 
@@ -46,61 +77,396 @@ impl Request {
 
 #[derive(Clone)]
 pub struct AsyncRequest {
+    method: String,
     pub path: String,
+    uri: Uri,
+    version: String,
     pub handler: Arc<AsyncHandler>,
     pub path_params: HashMap<String, String>,
     pub deps: Arc<DepsMap>,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Arc<Mutex<dyn ConnStream>>,
+    matched_route: Option<String>,
+    started_at: Instant,
+    limits: Limits,
+    // set by a JsonBodyLimit check, or the first call to json
+    json: Option<serde_json::Value>,
+    // shared with every clone, the handler's copy and the one writing the response count into the same totals
+    bytes_read: Arc<AtomicU64>,
+    bytes_written: Arc<AtomicU64>,
+    // Expect: 100-continue, the client holds the body back until it gets a 100 Continue
+    awaiting_continue: bool,
 }
 
 impl AsyncRequest {
-    pub fn create(path: &str, handler: Arc<AsyncHandler>, path_params: HashMap<String, String>, deps: Arc<DepsMap>, headers: HashMap<String, String>, body: Arc<Mutex<dyn ConnStream>>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(
+        method: &str,
+        path: &str,
+        version: &str,
+        handler: Arc<AsyncHandler>,
+        path_params: HashMap<String, String>,
+        deps: Arc<DepsMap>,
+        headers: Headers,
+        body: Arc<Mutex<dyn ConnStream>>,
+    ) -> Self {
+        let awaiting_continue = version == "HTTP/1.1" && headers.get("expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
         AsyncRequest {
+            method: method.to_string(),
             path: path.to_string(),
+            uri: Uri::parse(path),
+            version: version.to_string(),
             handler,
             path_params,
             deps,
             headers,
             body,
+            matched_route: None,
+            started_at: Instant::now(),
+            limits: Limits::default(),
+            json: None,
+            bytes_read: Arc::default(),
+            bytes_written: Arc::default(),
+            awaiting_continue,
         }
     }
 
-    pub async fn body(&self) -> Result<String, Error> {
-        // throw away \r\n\r\n which 4 chars
-        let mut buf = vec![0u8; 4];
-        loop {
-            match self.body.lock().unwrap().read_exact(&mut buf) {
-                Ok(_) => break,
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    // `path` split into its components
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    pub fn matched_route(&self) -> Option<&str> {
+        self.matched_route.as_deref()
+    }
+
+    // Put into the deps by the server builder, None for requests that did not come through one
+    pub fn server_config(&self) -> Option<&ServerConfig> {
+        self.deps.get::<ServerConfig>()
+    }
+
+    // The client asked for the connection to end with this response (Connection: close)
+    pub fn wants_close(&self) -> bool {
+        self.headers.has_token("connection", "close")
+    }
+
+    // Whether the connection may carry another request once this one is answered. Requests with a body end the connection, nothing
+    // guarantees the handler has read all of it.
+    pub(crate) fn can_pipeline(&self) -> bool {
+        // a declared empty body frames the request exactly, whatever follows it is the next request
+        let no_body = !self.headers.contains("content-length") || matches!(self.body_framing(), Ok(BodyFraming::Length(0)));
+        self.version == "HTTP/1.1" && !self.wants_close() && !self.headers.contains("transfer-encoding") && no_body
+    }
+
+    // Chunked responses (and trailers) are HTTP/1.1 only
+    pub(crate) fn accepts_chunked(&self) -> bool {
+        self.version == "HTTP/1.1"
+    }
+
+    // Head and as much of the body as has been read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    // Interim responses and the response, complete only once it has been written
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn count_read(&self, n: usize) {
+        self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn count_written(&self, n: usize) {
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // Time since the request head was read and dispatch began
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    // Writes an interim 1xx response (e.g. 103 Early Hints) right away, the handler's own response still follows.
+    // HTTP/1.0 clients do not understand 1xx, 101 is left to protocol upgrades.
+    pub fn send_informational(&self, status_code: u16, headers: Headers) -> Result<(), Error> {
+        if !(100..=199).contains(&status_code) || status_code == 101 {
+            return Err(Error::new(500, "Not an informational status code"));
+        }
+        if self.version != "HTTP/1.1" {
+            return Err(Error::new(500, "Informational responses need HTTP/1.1"));
+        }
+        let mut interim = Response::create(status_code, String::new());
+        interim.headers = headers;
+        let interim = interim.build_http_string();
+
+        let mut written = 0;
+        while written < interim.len() {
+            match self.body.lock().unwrap().write(&interim.as_bytes()[written..]) {
+                Ok(0) => return Err(Error::new(500, "Connection closed")),
+                Ok(n) => {
+                    written += n;
+                    self.count_written(n);
+                }
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
-                Err(_e) => panic!("Do we want to panic here"),
-            };
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::new_with_desc(500, "Could not write informational response", &e.to_string())),
+            }
         }
+        Ok(())
+    }
 
-        // TODO: header names to be case insensitive and
+    pub async fn body(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.body_bytes().await?).map_err(|_| Error::new(400, "Body is not valid UTF-8"))
+    }
+
+    // Parsed once, later calls (and handlers behind a JsonBodyLimit) get the same value without reading the body again
+    pub async fn json(&mut self) -> Result<&serde_json::Value, Error> {
+        if self.json.is_none() {
+            let body = self.body_bytes().await?;
+            let json = serde_json::from_slice(&body).map_err(|e| Error::new_with_desc(400, "Body is not valid JSON", &e.to_string()))?;
+            self.json = Some(json);
+        }
+        Ok(self.json.as_ref().unwrap())
+    }
+
+    // Takes &mut self as trailers of a chunked body end up in `headers`
+    // A gzip or deflate encoded body (Content-Encoding) comes back decoded, max_body_size applies to both the encoded and the decoded size
+    pub async fn body_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let content_encoding = self.headers.get("content-encoding").unwrap_or_default().to_string();
+        content_encoding::check_supported(&content_encoding)?;
+        let body = match self.body_framing()? {
+            BodyFraming::Chunked => {
+                self.send_continue()?;
+                self.read_chunked_body()?
+            }
+            BodyFraming::Length(content_length) if content_length > self.limits.max_body_size => return Err(ParseError::BodyTooLarge.into()),
+            BodyFraming::Length(content_length) => {
+                self.send_continue()?;
+                let mut buf = vec![0u8; content_length];
+                self.read_body_exact(&mut buf)?;
+                buf
+            }
+        };
+        let body = content_encoding::decode(&content_encoding, body, self.limits.max_body_size)?;
+        self.log_body(&body, body.len());
+        Ok(body)
+    }
+
+    // Only once a handler actually reads the body, one that answers without it spares the client the upload
+    pub(crate) fn send_continue(&mut self) -> Result<(), Error> {
+        if self.awaiting_continue {
+            self.send_informational(100, Headers::new())?;
+            self.awaiting_continue = false;
+        }
+        Ok(())
+    }
+
+    // See with_body_logging. Non printable bytes are escaped, so a binary body cannot mess up the log.
+    pub(crate) fn log_body(&self, start: &[u8], length: usize) {
+        if self.limits.logged_body_bytes == 0 {
+            return;
+        }
+        let shown = &start[..start.len().min(self.limits.logged_body_bytes)];
+        debug!(
+            "{method} {path} body, {length} bytes: {shown}{truncated}",
+            method = self.method,
+            path = self.path,
+            shown = shown.escape_ascii(),
+            truncated = if shown.len() < length { "..." } else { "" }
+        );
+    }
+
+    fn body_framing(&self) -> Result<BodyFraming, Error> {
         // TODO: should we handle cases where content length is uknown? check RFC
-        if let Some(content_length) = self.headers.get("content-length") {
+        if self.headers.has_token("transfer-encoding", "chunked") {
+            Ok(BodyFraming::Chunked)
+        } else if let Some(content_length) = self.headers.get("content-length") {
             debug!("Request content-length: {content_length}");
-            let mut buf = vec![0u8; content_length.clone().parse::<usize>().unwrap()];
-            loop {
-                match self.body.lock().unwrap().read_exact(&mut buf) {
-                    Ok(_) => break,
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
-                    Err(_e) => panic!("Do we want to panic here"),
-                };
+            // https://www.rfc-editor.org/rfc/rfc9110#section-8.6 - digits only, parse alone would also take a leading `+`
+            if content_length.is_empty() || !content_length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::BadContentLength.into());
             }
-            Ok(String::from_utf8(buf).unwrap())
+            let content_length = content_length.parse::<usize>().map_err(|_| ParseError::BadContentLength)?;
+            Ok(BodyFraming::Length(content_length))
         } else {
             Err(Error::new(411, "Missing Content-Length header"))
         }
     }
+
+    // Streams the body into a newly created (or truncated) file at `path` without buffering it, returns how many bytes were written.
+    // The file is removed again when the body cannot be read completely.
+    pub async fn body_to_file(&mut self, path: impl AsRef<Path>) -> Result<u64, Error> {
+        let path = path.as_ref();
+        let mut stream = self.body_stream()?;
+        let mut file = File::create(path).map_err(|e| Error::new_with_desc(500, "Could not create file", &e.to_string()))?;
+        let mut written = 0;
+        while let Some(piece) = stream.next().await {
+            let piece = piece.and_then(|piece| {
+                file.write_all(&piece).map_err(|e| Error::new_with_desc(500, "Could not write file", &e.to_string()))?;
+                Ok(piece.len() as u64)
+            });
+            match piece {
+                Ok(n) => written += n,
+                Err(e) => {
+                    drop(file);
+                    let _ = fs::remove_file(path);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    // Reads and drops a body nobody is going to read, unless it is larger than `max_length`. Closing a connection that still has
+    // unread data makes the kernel reset it, which can take the response down with it before the client has read it.
+    pub(crate) async fn discard_body(&mut self, max_length: usize) {
+        // a client waiting for 100 Continue has not sent anything yet
+        if self.awaiting_continue || (!self.headers.contains("content-length") && !self.headers.contains("transfer-encoding")) {
+            return;
+        }
+        let Ok(mut stream) = self.body_stream() else {
+            return;
+        };
+        let mut discarded = 0;
+        while discarded <= max_length {
+            match stream.next().await {
+                Some(Ok(piece)) => discarded += piece.len(),
+                _ => return,
+            }
+        }
+    }
+
+    // Reads the body piece by piece as it arrives instead of buffering all of it, e.g. to hash or forward a large upload.
+    // Takes &mut self for the same reason body_bytes does. The pieces come as sent, a Content-Encoding is not undone.
+    pub fn body_stream(&mut self) -> Result<BodyStream<'_>, Error> {
+        BodyStream::new(self)
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7230#section-4.1
+    fn read_chunked_body(&mut self) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::new();
+        while let Some(mut chunk) = self.read_chunk()? {
+            if body.len() + chunk.len() > self.limits.max_body_size {
+                return Err(ParseError::BodyTooLarge.into());
+            }
+            body.append(&mut chunk);
+        }
+        Ok(body)
+    }
+
+    // None after the last chunk, by then the trailers have been read as well
+    fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let size_line = self.read_body_line(MAX_TRAILER_LINE_LENGTH)?;
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::BadChunkSize)?;
+        debug!("Request chunk size: {size}");
+        if size > self.limits.max_chunk_size {
+            return Err(ParseError::ChunkTooLarge.into());
+        }
+        if size == 0 {
+            self.read_trailers()?;
+            return Ok(None);
+        }
+        let mut chunk = vec![0u8; size];
+        self.read_body_exact(&mut chunk)?;
+        if !self.read_body_line(MAX_TRAILER_LINE_LENGTH)?.is_empty() {
+            return Err(ParseError::UnterminatedChunk.into());
+        }
+        Ok(Some(chunk))
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7230#section-4.1.2 - trailers are merged into the headers, but never replace
+    // a header that came with the request head, nor carry framing information
+    fn read_trailers(&mut self) -> Result<(), Error> {
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_body_line(MAX_TRAILER_LINE_LENGTH)?;
+            if line.is_empty() {
+                break;
+            }
+            if lines.len() == MAX_TRAILER_COUNT {
+                return Err(ParseError::TooManyTrailers.into());
+            }
+            lines.push(line);
+        }
+        let trailers = Headers::try_from_lines(lines.iter().map(String::as_str))?;
+        for (name, value) in trailers.iter() {
+            let forbidden = ["content-length", "transfer-encoding", "trailer", "host"].contains(&name.to_lowercase().as_str());
+            if !forbidden && !self.headers.contains(name) {
+                self.headers.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_body_line(&self, max_length: usize) -> Result<String, Error> {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            if line.len() > max_length {
+                return Err(ParseError::ChunkLineTooLong.into());
+            }
+            self.read_body_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).map_err(|_| ParseError::ChunkLineNotUtf8.into())
+    }
+
+    // Whatever is available, at least one byte and at most `max_length`
+    fn read_body_some(&self, max_length: usize) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; max_length];
+        loop {
+            match self.body.lock().unwrap().read(&mut buf) {
+                Ok(0) => return Err(ParseError::UnexpectedEndOfBody.into()),
+                Ok(n) => {
+                    self.count_read(n);
+                    buf.truncate(n);
+                    return Ok(buf);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::new_with_desc(400, "Could not read body", &e.to_string())),
+            };
+        }
+    }
+
+    // Not read_exact, it loses whatever it has read so far when the stream would block halfway through
+    fn read_body_exact(&self, buf: &mut [u8]) -> Result<(), Error> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.body.lock().unwrap().read(&mut buf[filled..]) {
+                Ok(0) => return Err(ParseError::UnexpectedEndOfBody.into()),
+                Ok(n) => {
+                    filled += n;
+                    self.count_read(n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::new_with_desc(400, "Could not read body", &e.to_string())),
+            };
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Debug for AsyncRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("AsyncRequest").field("path", &self.path).field("path_params", &self.path_params).finish()
+        f.debug_struct("AsyncRequest")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .field("path_params", &self.path_params)
+            .finish()
     }
 }
 
@@ -110,18 +476,68 @@ impl PartialEq for AsyncRequest {
     }
 }
 
+const MAX_TRAILER_COUNT: usize = 32;
+const MAX_TRAILER_LINE_LENGTH: usize = 8190;
+
+#[allow(clippy::large_enum_variant)]
 #[derive(PartialEq, Clone, Debug)]
 pub enum ConnState {
     Read(Vec<u8>, usize),
-    Write(AsyncRequest, usize),
+    // the handler still has to run
+    Write(AsyncRequest),
+    // the handler is done, its response is partially written
+    Respond(PendingResponse),
+    // Half closed, whatever the client still sends gets drained until it closes too or the deadline passes
+    Closing(Instant),
+    // Done with the connection, it gets dropped (or half closed, see with_graceful_close). One that stays open for a pipelined
+    // request goes straight back to Read instead.
     Flush,
 }
 
+#[derive(PartialEq, Clone, Debug)]
+pub struct PendingResponse {
+    req: AsyncRequest,
+    status_code: StatusCode,
+    bytes: Vec<u8>,
+    written: usize,
+    // the connection is not reused after a server error
+    failed: bool,
+    // what comes after `bytes`, see Response::streaming
+    stream: Option<ResponseStream>,
+}
+
+impl PendingResponse {
+    // Matches the Connection: close the response got, see handle_async_better
+    pub(crate) fn keeps_alive(&self) -> bool {
+        !self.failed && self.req.can_pipeline()
+    }
+}
+
+impl ConnState {
+    // Only a connection waiting for the client's next bytes is polled for readability alone. Writable it nearly always is, so an idle
+    // keep-alive connection would otherwise get dispatched on every round. A draining one keeps both, so its deadline gets checked.
+    pub(crate) fn awaits_write(&self) -> bool {
+        !matches!(self, ConnState::Read(..))
+    }
+
+    // Unchanged across an event means the connection made no progress, see with_idle_timeout
+    pub(crate) fn progress(&self) -> (mem::Discriminant<ConnState>, usize) {
+        let done = match self {
+            ConnState::Read(buf, _) => buf.len(),
+            ConnState::Respond(pending) => pending.req.bytes_written() as usize,
+            _ => 0,
+        };
+        (mem::discriminant(self), done)
+    }
+}
+
 impl fmt::Display for ConnState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             ConnState::Read(_, _) => write!(f, "Read"),
-            ConnState::Write(_, _) => write!(f, "Write"),
+            ConnState::Write(_) => write!(f, "Write"),
+            ConnState::Respond(_) => write!(f, "Respond"),
+            ConnState::Closing(_) => write!(f, "Closing"),
             ConnState::Flush => write!(f, "Flush"),
         }
     }
@@ -155,6 +571,8 @@ pub struct Error {
     pub status_code: u16,
     pub title: String,
     pub desc: String,
+    // set when the request itself was malformed, see ParseError
+    pub parse_error: Option<ParseError>,
 }
 
 impl Error {
@@ -163,6 +581,7 @@ impl Error {
             status_code,
             title: title.to_string(),
             desc: "".to_string(),
+            parse_error: None,
         }
     }
 
@@ -172,6 +591,44 @@ impl Error {
             status_code,
             title: title.to_string(),
             desc: desc.to_string(),
+            parse_error: None,
         }
     }
 }
+
+// Lets handlers returning Result<_, Error> use `?` on I/O, the io error itself only ends up in `desc`
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        let status_code = match err.kind() {
+            io::ErrorKind::NotFound => 404,
+            io::ErrorKind::PermissionDenied => 403,
+            io::ErrorKind::TimedOut => 504,
+            _ => 500,
+        };
+        Error::new_with_desc(status_code, &HttpStatus::get_status_msg(status_code), &err.to_string())
+    }
+}
+
+// Handlers failing with a plain message respond with a 500 carrying that message
+impl From<String> for Error {
+    fn from(msg: String) -> Error {
+        Error::new(500, &msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::Error;
+
+    #[test]
+    fn io_errors_map_to_status_codes() {
+        let status_of = |kind: io::ErrorKind| Error::from(io::Error::new(kind, "boom")).status_code;
+
+        assert_eq!(status_of(io::ErrorKind::NotFound), 404);
+        assert_eq!(status_of(io::ErrorKind::PermissionDenied), 403);
+        assert_eq!(status_of(io::ErrorKind::TimedOut), 504);
+        assert_eq!(status_of(io::ErrorKind::ConnectionReset), 500);
+    }
+}
@@ -1,53 +1,116 @@
+use crate::futures::catch_unwind::{panic_message, CatchUnwind};
 use crate::http::async_handler::AsyncHandler;
 use crate::http::ConnState;
+use crate::log_panic;
 use kqueue_sys::EventFlag;
-use log::debug;
+use log::{debug, error};
 use std::net::TcpListener;
 use std::os::fd::AsRawFd;
-use std::{io, sync::atomic::Ordering};
+use std::time::{Duration, Instant};
+use std::{io, sync::atomic::Ordering, thread};
 
-use super::async_http_server::{AsyncHttpServer, AsyncHttpServerBuilder, AsyncHttpServerTrt};
+use super::async_http_server::{AsyncHttpServer, AsyncHttpServerBuilder, AsyncHttpServerTrt, POLL_TIMEOUT, SWEEP_INTERVAL};
 
 impl AsyncHttpServerTrt for AsyncHttpServer {
-    fn start_blocking(&self) {
-        let listener = TcpListener::bind(&self.listen_addr).unwrap();
-        listener.set_nonblocking(true).unwrap();
+    fn try_start_blocking(&self) -> io::Result<()> {
+        let listeners = self.bind_listeners()?;
+
+        thread::scope(|scope| {
+            listeners.into_iter().enumerate().for_each(|(id, listeners)| {
+                thread::Builder::new()
+                    .name(format!("acceptor-{id}"))
+                    .spawn_scoped(scope, move || self.accept_loop(listeners))
+                    .unwrap_or_else(|e| log_panic!("Failed to spawn acceptor thread, reason:\n{reason}", reason = e.to_string()));
+            })
+        });
+        Ok(())
+    }
+
+    fn builder() -> AsyncHttpServerBuilder {
+        AsyncHttpServerBuilder::default()
+    }
+
+    fn shutdown_gracefully(self) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        self.workers.poison_all()
+    }
+
+    fn shutdown_gracefully_timeout(self, timeout: Duration) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        self.workers.poison_all_timeout(timeout);
+    }
+}
+
+impl AsyncHttpServer {
+    // each acceptor owns its kqueue, connections accepted here are only ever polled here
+    fn accept_loop(&self, listeners: Vec<TcpListener>) {
         let kqueue = unsafe { kqueue_sys::kqueue() };
-        let sock_kevent = kqueue_sys::kevent::new(
-            listener.as_raw_fd() as usize,
-            kqueue_sys::EventFilter::EVFILT_READ,
-            kqueue_sys::EventFlag::EV_ADD | kqueue_sys::EventFlag::EV_ENABLE,
-            kqueue_sys::FilterFlag::empty(),
-        );
-        let socket_kevent_result = unsafe { kqueue_sys::kevent(kqueue, &sock_kevent, 1, core::ptr::null_mut(), 0, core::ptr::null()) };
-        if socket_kevent_result == -1 {
-            panic!("could not register change event on kqueue for the socket");
+        for listener in &listeners {
+            let sock_kevent = kqueue_sys::kevent::new(
+                listener.as_raw_fd() as usize,
+                kqueue_sys::EventFilter::EVFILT_READ,
+                kqueue_sys::EventFlag::EV_ADD | kqueue_sys::EventFlag::EV_ENABLE,
+                kqueue_sys::FilterFlag::empty(),
+            );
+            let socket_kevent_result = unsafe { kqueue_sys::kevent(kqueue, &sock_kevent, 1, core::ptr::null_mut(), 0, core::ptr::null()) };
+            if socket_kevent_result == -1 {
+                panic!("could not register change event on kqueue for the socket");
+            }
         }
 
+        let timeout = libc::timespec {
+            tv_sec: POLL_TIMEOUT.as_secs() as _,
+            tv_nsec: POLL_TIMEOUT.subsec_nanos() as _,
+        };
+        let mut next_sweep = Instant::now() + SWEEP_INTERVAL;
         loop {
-            self.started.store(true, std::sync::atomic::Ordering::SeqCst);
+            if self.should_stop() {
+                return;
+            }
+            self.mark_started();
             // extract this, the contents does not matter
             let mut kevent = kqueue_sys::kevent::new(0, kqueue_sys::EventFilter::EVFILT_WRITE, kqueue_sys::EventFlag::empty(), kqueue_sys::FilterFlag::empty());
-            let events_number = unsafe { kqueue_sys::kevent(kqueue, core::ptr::null(), 0, &mut kevent, 1, core::ptr::null()) };
+            let events_number = unsafe { kqueue_sys::kevent(kqueue, core::ptr::null(), 0, &mut kevent, 1, &timeout) };
             if events_number == -1 {
+                // a signal arrived, might be the one asking us to stop
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
                 panic!("could not retrieve an event from kqueue");
             }
+            if Instant::now() >= next_sweep {
+                self.sweep_idle_connections();
+                next_sweep = Instant::now() + SWEEP_INTERVAL;
+            }
+            if events_number == 0 {
+                continue;
+            }
             debug!("Events count: {events_number}");
 
-            if kevent.ident as i32 == listener.as_raw_fd() {
+            if let Some(listener) = listeners.iter().find(|listener| listener.as_raw_fd() == kevent.ident as i32) {
                 match listener.accept() {
                     Ok((connection, _)) => {
                         connection.set_nonblocking(true).expect("Could not set.");
                         let fd = connection.as_raw_fd();
+                        // carried by every event for the connection, see conn_id
+                        let id = self.conn_id(fd);
 
-                        let conn_kevent = kqueue_sys::kevent::new(fd as usize, kqueue_sys::EventFilter::EVFILT_READ, kqueue_sys::EventFlag::EV_ADD, kqueue_sys::FilterFlag::empty());
+                        let mut conn_kevent = kqueue_sys::kevent::new(fd as usize, kqueue_sys::EventFilter::EVFILT_READ, kqueue_sys::EventFlag::EV_ADD, kqueue_sys::FilterFlag::empty());
+                        conn_kevent.udata = id as usize as _;
                         let conn_kevent_result = unsafe { kqueue_sys::kevent(kqueue, &conn_kevent, 1, core::ptr::null_mut(), 0, core::ptr::null()) };
                         if conn_kevent_result < 0 {
                             // maybe we don't wanna blow up here?
                             panic!("Cannot register filter event for connection.");
                         }
 
-                        let conn_kevent = kqueue_sys::kevent::new(fd as usize, kqueue_sys::EventFilter::EVFILT_WRITE, kqueue_sys::EventFlag::EV_ADD, kqueue_sys::FilterFlag::empty());
+                        // enabled only while there is something to write, see ConnState::awaits_write
+                        let mut conn_kevent = kqueue_sys::kevent::new(
+                            fd as usize,
+                            kqueue_sys::EventFilter::EVFILT_WRITE,
+                            kqueue_sys::EventFlag::EV_ADD | kqueue_sys::EventFlag::EV_DISABLE,
+                            kqueue_sys::FilterFlag::empty(),
+                        );
+                        conn_kevent.udata = id as usize as _;
                         let conn_kevent_result = unsafe { kqueue_sys::kevent(kqueue, &conn_kevent, 1, core::ptr::null_mut(), 0, core::ptr::null()) };
                         if conn_kevent_result < 0 {
                             // maybe we don't wanna blow up here?
@@ -57,7 +120,7 @@ impl AsyncHttpServerTrt for AsyncHttpServer {
                         let state = ConnState::Read(Vec::new(), 0);
 
                         debug!("Insert event id: {fd}");
-                        self.connections.lock().expect("locking problem").insert(fd, (connection, state));
+                        self.connections.lock().expect("locking problem").insert(id, (connection, state, Instant::now()));
                     }
                     Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
                     Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
@@ -65,25 +128,64 @@ impl AsyncHttpServerTrt for AsyncHttpServer {
                     Err(e) => panic!("failed to accept: {}", e),
                 }
             } else {
-                let endpoints = self.endpoints.clone();
+                let endpoints = self.endpoints_snapshot();
                 let conns = self.connections.clone();
 
                 let fd = kevent.ident as i32;
+                let id = kevent.udata as usize as u64;
                 debug!("Got event id: {fd}");
 
-                let option = conns.lock().expect("Poisoned").remove(&fd);
-                if let Some((conn, conn_status)) = option {
+                let option = conns.lock().expect("Poisoned").remove(&id);
+                if let Some((conn, conn_status, last_active)) = option {
                     if kevent.flags.contains(EventFlag::EV_EOF) || conn_status == ConnState::Flush {
                         drop(conn);
                     } else {
-                        let deps_map = self.deps_map.clone();
+                        let deps_map = self.deps_snapshot();
+                        let limits = self.limits;
+                        let propagate_panics = self.propagate_panics;
+                        let fallback = self.fallback.clone();
+                        let default_headers = self.default_headers.clone();
+                        // a connection that has been draining is dropped once it is done, not half closed again
+                        let close_timeout = self.close_timeout.filter(|_| !matches!(conn_status, ConnState::Closing(_)));
+                        let progress = conn_status.progress();
                         let result = self
                             .workers
-                            .queue_with_result(async move { AsyncHandler::handle_async_better(conn, &conn_status, endpoints, deps_map).await })
+                            // a propagated handler panic would otherwise leave this acceptor waiting for a result forever
+                            .queue_with_result(async move {
+                                CatchUnwind::new(AsyncHandler::handle_async_better(
+                                    conn,
+                                    conn_status,
+                                    endpoints,
+                                    fallback,
+                                    deps_map,
+                                    limits,
+                                    default_headers,
+                                    propagate_panics,
+                                ))
+                                .await
+                                .unwrap_or_else(|e| {
+                                    error!(
+                                        "Handler panicked, dropping connection: {reason}",
+                                        reason = panic_message(e.as_ref()).unwrap_or("cannot interpret panic")
+                                    );
+                                    None
+                                })
+                            })
                             .expect("Could not retrieve result from future.")
                             .get();
                         if let Some((conn, conn_state)) = result {
-                            conns.lock().expect("Poisoned").insert(fd, (conn, conn_state));
+                            let conn_state = match conn_state {
+                                ConnState::Flush => close_timeout.and_then(|timeout| AsyncHttpServer::closing_state(&conn, timeout)).unwrap_or(ConnState::Flush),
+                                conn_state => conn_state,
+                            };
+                            let last_active = AsyncHttpServer::last_active(progress, &conn_state, last_active);
+                            // without the interest it needs the connection would never get another event, it is dropped instead
+                            match Self::update_write_interest(kqueue, fd, id, &conn_state) {
+                                Ok(()) => {
+                                    conns.lock().expect("Poisoned").insert(id, (conn, conn_state, last_active));
+                                }
+                                Err(e) => error!("Could not update interest in connection {fd}, dropping it: {e}"),
+                            }
                         }
                     }
                 }
@@ -91,12 +193,13 @@ impl AsyncHttpServerTrt for AsyncHttpServer {
         }
     }
 
-    fn builder() -> AsyncHttpServerBuilder {
-        AsyncHttpServerBuilder::default()
-    }
-
-    fn shutdown_gracefully(self) {
-        self.shutdown_requested.store(true, Ordering::SeqCst);
-        self.workers.poison_all()
+    fn update_write_interest(kqueue: i32, fd: i32, id: u64, state: &ConnState) -> io::Result<()> {
+        let toggle = if state.awaits_write() { EventFlag::EV_ENABLE } else { EventFlag::EV_DISABLE };
+        let mut conn_kevent = kqueue_sys::kevent::new(fd as usize, kqueue_sys::EventFilter::EVFILT_WRITE, toggle, kqueue_sys::FilterFlag::empty());
+        conn_kevent.udata = id as usize as _;
+        if unsafe { kqueue_sys::kevent(kqueue, &conn_kevent, 1, core::ptr::null_mut(), 0, core::ptr::null()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
     }
 }
@@ -1,140 +1,273 @@
 use crate::typemap::DepsMap;
 
+use super::compiled_path::CompiledPath;
+use super::headers::{Headers, MediaType};
+use super::json_body::JsonBodyLimit;
+use super::limits::Limits;
+use super::middleware::{Middleware, Next};
+use super::response_builder::IntoResponse;
+use super::server_config::ServerConfig;
+use super::uri::Uri;
 use super::ConnStream;
-use super::{helpers, response::Response, AsyncRequest, ConnState};
-use crate::futures::catch_unwind::CatchUnwind;
+use super::{
+    helpers::{self, HeadError},
+    response::Response,
+    AsyncRequest, ConnState, Error, PendingResponse,
+};
+use crate::futures::catch_unwind::{self, panic_message, CatchUnwind};
 use log::{debug, error};
+use std::any::{type_name, Any, TypeId};
 use std::collections::{HashMap, HashSet};
-use std::str::from_utf8;
 use std::sync::Arc;
+use std::time::Instant;
 use std::{future::Future, io, pin::Pin};
 
+// Registering a handler with this method makes it handle every method that has no route of its own
+pub const ANY_METHOD: &str = "*";
+
+// TRACE echoes the request back (cross-site tracing) and CONNECT means nothing to an origin server. Neither is served by
+// ANY_METHOD or the fallback, only by a route registered for it by name, everything else gets a 405.
+pub const EXPLICIT_ONLY_METHODS: [&str; 2] = ["TRACE", "CONNECT"];
+
+// Bodies of requests nothing was routed to get read up to this size, see AsyncRequest::discard_body
+const MAX_DISCARDED_BODY: usize = 64 * 1024;
+
 pub struct AsyncHandler {
     pub method: String,
     pub path: String,
     pub func: Box<dyn AsyncHandlerFn + Sync>,
+    pub consumes: Option<String>,
+    pub produces: Option<String>,
+    // kept sorted, so handlers that differ only in the order constraints were added in are equal
+    pub query_required: Vec<(String, String)>,
+    pub json_body_limit: Option<JsonBodyLimit>,
+    // normalized, see helpers::normalize_host. None serves every host.
+    pub host: Option<String>,
+    // run in order before `func`, see Middleware
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    // free form tags for middlewares to act on, e.g. requires_auth. Not part of the route's identity.
+    pub meta: HashMap<String, String>,
+    // deps the handler cannot do without, checked against the server's deps before it serves anything. Not part of the route's identity.
+    pub required_deps: Vec<(TypeId, &'static str)>,
+    pub(crate) compiled_path: CompiledPath,
 }
 
 impl AsyncHandler {
-    pub async fn handle_async_better<S>(mut connection: S, conn_state: &ConnState, endpoints: HashSet<Arc<AsyncHandler>>, deps_map: Arc<DepsMap>) -> Option<(S, ConnState)>
+    #[allow(clippy::too_many_arguments)]
+    pub async fn handle_async_better<S>(
+        mut connection: S,
+        conn_state: ConnState,
+        endpoints: HashSet<Arc<AsyncHandler>>,
+        fallback: Option<Arc<AsyncHandler>>,
+        deps_map: Arc<DepsMap>,
+        limits: Limits,
+        default_headers: Arc<Headers>,
+        propagate_panics: bool,
+    ) -> Option<(S, ConnState)>
     where
         S: ConnStream,
     {
         match conn_state {
-            ConnState::Read(req, read_bytes) => {
-                let mut buf = [0u8; 8192];
-                match connection.peek(&mut buf) {
-                    Ok(_) => {}
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Some((connection, ConnState::Read(req.clone(), *read_bytes))),
-                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Some((connection, ConnState::Read(req.clone(), *read_bytes))),
-                    Err(e) => {
-                        error!("Unpeekable stream. Error: {e}");
+            ConnState::Read(mut buf, read_bytes) => {
+                let read = helpers::read_http_request(&mut connection, &mut buf, limits);
+                // an oversized target is rejected before the head is complete, or has grown past the head size limit
+                if let Err(e) = helpers::check_uri_length(&buf, limits) {
+                    debug!("Rejecting request: {title} ({reason:?})", title = e.title, reason = e.parse_error);
+                    let rejected = Self::rejected(e, &connection);
+                    return Some((connection, ConnState::Write(rejected)));
+                }
+                let http_req_size = match read {
+                    Ok(Some(n)) => n,
+                    Ok(None) => return Some((connection, ConnState::Read(buf, read_bytes))),
+                    Err(HeadError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => return Some((connection, ConnState::Read(buf, read_bytes))),
+                    Err(HeadError::Io(e)) if e.kind() == io::ErrorKind::InvalidInput => return Some((connection, ConnState::Read(buf, read_bytes))),
+                    // the client went away, be it between requests or halfway through one. Nothing to answer, the connection just gets closed.
+                    Err(HeadError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        debug!("Client closed the connection after {read} bytes of a request head", read = buf.len());
                         return Some((connection, ConnState::Flush));
                     }
-                }
-                let http_req_size = match from_utf8(&buf).expect("string").find("\r\n\r\n") {
-                    Some(n) => n,
-                    None => {
-                        error!("Received not an HTTP request.");
+                    Err(HeadError::Io(e)) => {
+                        error!("Could not read http request. Error: {e}");
                         return Some((connection, ConnState::Flush));
                     }
-                };
-                let mut buf = vec![0u8; http_req_size];
-                match connection.read_exact(&mut buf) {
-                    Ok(()) => {
-                        debug!("Read http req.");
+                    Err(HeadError::Rejected(e)) => {
+                        debug!("Rejecting request: {title} ({reason:?})", title = e.title, reason = e.parse_error);
+                        let rejected = Self::rejected(e, &connection);
+                        return Some((connection, ConnState::Write(rejected)));
                     }
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Some((connection, ConnState::Read(req.clone(), *read_bytes))),
-                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Some((connection, ConnState::Read(req.clone(), *read_bytes))),
-                    Err(e) => panic!("{}", e), // TODO: probably don't wanna blow up here
                 };
+                debug!("Read http req.");
 
-                let raw_req = String::from_utf8_lossy(&buf);
-                let request: Vec<&str> = raw_req.split('\n').collect();
-
-                let first_line: Vec<&str> = request[0].split(' ').collect();
-                let method = first_line[0];
-                let path = first_line[1];
-                let _protocol = first_line[2];
-                let headers = &request[1..]
-                    .iter()
-                    .map(|x| {
-                        if x.contains(':') {
-                            let split = x.split_once(':').unwrap();
-                            (split.0.trim().to_string().to_lowercase(), split.1.trim().to_string().to_lowercase())
-                        } else {
-                            (x.trim().to_string(), "".to_string())
-                        }
-                    })
-                    .collect::<HashMap<String, String>>();
+                let head = match helpers::parse_request_head(&buf[..http_req_size - 4], limits) {
+                    Ok(head) => head,
+                    Err(e) => {
+                        debug!("Rejecting unparsable request: {title} ({reason:?})", title = e.title, reason = e.parse_error);
+                        let rejected = Self::rejected(e, &connection);
+                        return Some((connection, ConnState::Write(rejected)));
+                    }
+                };
+                // before routing, the handler sees the overridden method as the request's method
+                let overridden = deps_map.get::<ServerConfig>().filter(|config| config.method_override).and_then(|_| helpers::overridden_method(&head));
+                let method = overridden.as_deref().unwrap_or(&head.method);
+                let path = head.path.as_str();
+                let version = head.protocol.as_str();
+                let headers = &head.headers;
 
                 debug!("http_req_size = {http_req_size}; ");
 
-                let endpoint = endpoints.iter().find(|x| x.method == method && helpers::path_matches_pattern(&x.path, path));
+                let uri = Uri::parse(path);
+                let host = headers.host().map(helpers::normalize_host);
+                // an exact method always wins over a wildcard registered for the same path. Among those a route for the request's host wins,
+                // then the route with the most query constraints.
+                let route_for = |route_method: &str| {
+                    endpoints
+                        .iter()
+                        .filter(|x| x.method == route_method && x.matches(&uri, host.as_deref()))
+                        .max_by_key(|x| (x.host.is_some(), x.query_required.len()))
+                };
+                let explicit_only = EXPLICIT_ONLY_METHODS.contains(&method);
+                let endpoint = route_for(method).or_else(|| if explicit_only { None } else { route_for(ANY_METHOD) });
 
-                debug!("Request payload: {:?}", request);
+                debug!("Request headers: {:?}", headers);
 
-                let req_handler = match endpoint {
+                let mut req_handler = match endpoint {
+                    None if explicit_only => {
+                        debug!("Method {method} has no route of its own for path: '{path}'");
+                        AsyncRequest::create(
+                            method,
+                            path,
+                            version,
+                            Arc::new(AsyncHandler::error(Error::new(405, "Method Not Allowed"))),
+                            HashMap::new(),
+                            deps_map,
+                            headers.clone(),
+                            connection.try_clone().unwrap(),
+                        )
+                    }
                     None => {
                         debug!("No handler registered for path: '{path}' and method: {method} not found.");
                         AsyncRequest::create(
+                            method,
+                            path,
+                            version,
+                            fallback.unwrap_or_else(|| Arc::new(AsyncHandler::not_found(method, path))),
+                            HashMap::new(),
+                            deps_map,
+                            headers.clone(),
+                            connection.try_clone().unwrap(),
+                        )
+                    }
+                    Some(endpoint) if !endpoint.accepts(headers.content_type()) => {
+                        debug!(
+                            "Content-Type: {content_type:?} not accepted by '{endpoint_path}'",
+                            content_type = headers.get("content-type"),
+                            endpoint_path = endpoint.path
+                        );
+                        AsyncRequest::create(
+                            method,
                             path,
-                            Arc::new(AsyncHandler::not_found(method)),
+                            version,
+                            Arc::new(AsyncHandler::error(Error::new(415, "Unsupported Media Type"))),
                             HashMap::new(),
-                            Arc::new(DepsMap::default()),
+                            deps_map,
                             headers.clone(),
                             connection.try_clone().unwrap(),
                         )
                     }
                     Some(endpoint) => {
                         debug!("Path: '{path}' and endpoint.path: '{endpoint_path}'", endpoint_path = endpoint.path);
-                        AsyncRequest::create(
+                        let mut req = AsyncRequest::create(
+                            method,
                             path,
+                            version,
                             endpoint.clone(),
-                            helpers::extract_path_params(&endpoint.path, path),
+                            endpoint.compiled_path.extract_params(uri.path()),
                             deps_map,
                             headers.clone(),
                             connection.try_clone().unwrap(),
-                        )
+                        );
+                        req.matched_route = Some(endpoint.compiled_path.pattern().to_string());
+                        req
                     }
                 };
-                Some((connection, ConnState::Write(req_handler, 0)))
+                // the fallback reads bodies too, and the 405 and 415 discard them
+                req_handler.limits = limits;
+                req_handler.count_read(http_req_size);
+                Some((connection, ConnState::Write(req_handler)))
             }
-            ConnState::Write(req, written_bytes) => {
-                let res = CatchUnwind::new(req.handler.func.call(req.clone()))
-                    .await
-                    .unwrap_or_else(|e| {
-                        Ok(if e.is::<&str>() {
-                            let panic_msg = *e.downcast::<&str>().expect("&str");
-                            Response::create(500, format!("Internal server error\n:{panic_msg}"))
-                        } else if e.is::<String>() {
-                            let panic_msg = *e.downcast::<String>().expect("String");
-                            Response::create(500, format!("Internal server error\n:{panic_msg}"))
-                        } else {
-                            Response::create(500, "Cannot interpret error.".to_string())
+            ConnState::Write(mut req) => {
+                let checked = match req.handler.json_body_limit {
+                    Some(limit) => limit.check(&mut req).await,
+                    None => Ok(()),
+                };
+                // with propagation on a handler panic unwinds through here, up to whoever polls this future
+                let res = if let Err(e) = checked {
+                    Err(e)
+                } else if propagate_panics {
+                    Next::new(req.handler.clone()).run(req.clone()).await
+                } else {
+                    CatchUnwind::new(Next::new(req.handler.clone()).run(req.clone())).await.unwrap_or_else(|e| {
+                        // the backtrace only goes to the log, the client just learns that something went wrong
+                        let backtrace = catch_unwind::take_backtrace().map(|backtrace| format!("\nstack backtrace:\n{backtrace}")).unwrap_or_default();
+                        error!(
+                            "Handler for {method} {path} panicked: {reason}{backtrace}",
+                            method = req.method(),
+                            path = req.path,
+                            reason = panic_message(e.as_ref()).unwrap_or("cannot interpret panic")
+                        );
+                        Ok(match panic_message(e.as_ref()) {
+                            Some(panic_msg) => Response::error_for(&req.headers, 500, &format!("Internal server error\n:{panic_msg}")),
                             // [FL] TODO: custom error handlers
+                            None => Response::error_for(&req.headers, 500, "Cannot interpret error."),
                         })
                     })
-                    .unwrap();
-                let status_line = res.get_status_line();
-                let contents = res.response_body;
-                let length = contents.len();
-                let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
-                let response_len = response.len();
-                let mut written = *written_bytes;
-                while written != response_len {
-                    match connection.write(&response.as_bytes()[written..]) {
-                        Ok(0) => {
-                            debug!("client hung up");
-                            return Some((connection, ConnState::Flush));
-                        }
-                        Ok(n) => written += n,
-                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Some((connection, ConnState::Write(req.clone(), written))),
-                        Err(ref err) if err.kind() == io::ErrorKind::InvalidInput => return Some((connection, ConnState::Write(req.clone(), written))),
-                        Err(err) => panic!("{}", err), // I guess we don't wanna die here ?
+                };
+                // the connection is not reused after a server error, the client gets told so it does not try to either. A 4xx, returned or
+                // built-in, leaves it open, a request whose body could be left unread closes it anyway, see can_pipeline
+                let failed = match &res {
+                    Ok(res) => res.status_code >= 500,
+                    Err(e) => e.status_code >= 500,
+                };
+                // errors (the built-in 405 and 415 among them) come out in whatever error format the client accepts
+                let mut res = res.unwrap_or_else(|e| Response::error_for(&req.headers, e.status_code, &e.title));
+                // every response that ends the connection says so, a keep-alive client would otherwise send its next request into a closed socket
+                if failed || !req.can_pipeline() {
+                    res.headers.insert("Connection", "close");
+                }
+                for (name, value) in default_headers.iter() {
+                    if !res.headers.contains(name) {
+                        res.headers.insert(name, value);
+                    }
+                }
+                if let Some(produces) = &req.handler.produces {
+                    if !res.headers.contains("content-type") {
+                        res.headers.insert("Content-Type", produces);
+                    }
+                }
+                // the handler runs once, a write that cannot finish right away resumes from these bytes
+                let has_body = res.has_body();
+                let pending = PendingResponse {
+                    status_code: res.status_code,
+                    bytes: res.build_http_string_for(req.accepts_chunked()).into_bytes(),
+                    written: 0,
+                    failed,
+                    stream: res.stream.filter(|_| has_body),
+                    req,
+                };
+                Self::send(connection, pending)
+            }
+            ConnState::Respond(pending) => Self::send(connection, pending),
+            ConnState::Closing(deadline) => {
+                let mut drained = [0; 4096];
+                loop {
+                    match connection.read(&mut drained) {
+                        Ok(0) => return Some((connection, ConnState::Flush)),
+                        Ok(_) => continue,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => return Some((connection, ConnState::Closing(deadline))),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(_) => return Some((connection, ConnState::Flush)),
                     }
                 }
-                Some((connection, ConnState::Flush))
             }
             ConnState::Flush => {
                 if let Err(msg) = connection.flush() {
@@ -150,7 +283,7 @@ impl Eq for AsyncHandler {}
 
 impl PartialEq for AsyncHandler {
     fn eq(&self, other: &Self) -> bool {
-        self.method == other.method && self.path == other.path
+        self.method == other.method && self.path == other.path && self.query_required == other.query_required && self.host == other.host
     }
 }
 
@@ -158,6 +291,8 @@ impl std::hash::Hash for AsyncHandler {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.method.hash(state);
         self.path.hash(state);
+        self.query_required.hash(state);
+        self.host.hash(state);
     }
 }
 
@@ -167,42 +302,256 @@ impl AsyncHandler {
             method: method.to_string(),
             path: path.to_string(),
             func: Box::new(func),
+            consumes: None,
+            produces: None,
+            query_required: Vec::new(),
+            json_body_limit: None,
+            host: None,
+            middlewares: Vec::new(),
+            meta: HashMap::new(),
+            required_deps: Vec::new(),
+            compiled_path: CompiledPath::compile(path),
+        }
+    }
+
+    // Every call gets its own clone of `state`, saves the handler from cloning the Arc itself
+    pub fn from_fn_with_state<S, F, Fut, R>(method: &str, path: &str, state: Arc<S>, func: F) -> AsyncHandler
+    where
+        S: Send + Sync + 'static,
+        F: Fn(Arc<S>, AsyncRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoResponse,
+    {
+        AsyncHandler::new(method, path, move |req| func(state.clone(), req))
+    }
+
+    // Requests with any other Content-Type (or none) are rejected with a 415
+    pub fn consumes(mut self, content_type: &str) -> AsyncHandler {
+        self.consumes = Some(content_type.to_string());
+        self
+    }
+
+    // Used as the response Content-Type unless the handler sets one itself
+    pub fn produces(mut self, content_type: &str) -> AsyncHandler {
+        self.produces = Some(content_type.to_string());
+        self
+    }
+
+    // The route only matches when the query has `name` set to exactly `value`, otherwise other routes (or the 404) get a chance
+    pub fn with_query_required(mut self, name: &str, value: &str) -> AsyncHandler {
+        self.query_required.push((name.to_string(), value.to_string()));
+        self.query_required.sort();
+        self
+    }
+
+    // The body gets read and checked before the handler runs, see JsonBodyLimit
+    pub fn with_json_body_limit(mut self, limit: JsonBodyLimit) -> AsyncHandler {
+        self.json_body_limit = Some(limit);
+        self
+    }
+
+    // Runs after the middlewares added before it, and after those the server applies to every route
+    pub fn with_middleware(mut self, middleware: impl Middleware) -> AsyncHandler {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    // Middlewares find the route a request got matched to in `req.handler`, e.g. `req.handler.meta("requires_auth")`
+    pub fn with_meta(mut self, key: &str, value: &str) -> AsyncHandler {
+        self.meta.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    // A server missing `T` in its deps refuses to build, try_build returns a MissingDep, instead of the handler finding out on its first request
+    pub fn with_required_dep<T: Any + Send + Sync>(mut self) -> AsyncHandler {
+        self.required_deps.push((TypeId::of::<T>(), type_name::<T>()));
+        self
+    }
+
+    pub fn meta(&self, key: &str) -> Option<&str> {
+        self.meta.get(key).map(String::as_str)
+    }
+
+    // Puts the server wide middlewares in front of the route's own ones
+    pub(crate) fn behind(mut self, middlewares: &[Arc<dyn Middleware>]) -> AsyncHandler {
+        self.middlewares.splice(0..0, middlewares.iter().cloned());
+        self
+    }
+
+    // Mounts the route under `prefix`, parameters in the prefix end up next to the route's own ones
+    pub(crate) fn prefixed(mut self, prefix: &str) -> AsyncHandler {
+        let prefix = prefix.trim_end_matches('/');
+        self.path = match self.path.as_str() {
+            "/" | "" => prefix.to_string(),
+            path => format!("{prefix}{path}"),
+        };
+        self.compiled_path = CompiledPath::compile(&self.path);
+        self
+    }
+
+    // Only requests whose Host header names `host` get routed here, they prefer this route over one serving every host
+    pub(crate) fn for_host(mut self, host: &str) -> AsyncHandler {
+        self.host = Some(helpers::normalize_host(host));
+        self
+    }
+
+    fn matches(&self, uri: &Uri, host: Option<&str>) -> bool {
+        (self.host.is_none() || self.host.as_deref() == host)
+            && self.compiled_path.matches(uri.path())
+            && self
+                .query_required
+                .iter()
+                .all(|(name, value)| uri.query_pairs().any(|(query_name, query_value)| query_name == name && query_value == value))
+    }
+
+    // Only the media type is compared, parameters like charset are ignored
+    fn accepts(&self, content_type: Option<MediaType>) -> bool {
+        match (&self.consumes, content_type) {
+            (None, _) => true,
+            (Some(consumes), Some(content_type)) => content_type.is(consumes),
+            (Some(_), None) => false,
         }
     }
 
-    pub(crate) fn not_found(method: &str) -> AsyncHandler {
-        async fn not_found_fn(req: AsyncRequest) -> Result<Response, String> {
-            Ok(Response::create(404, format!("Resource: {req_path} not found.", req_path = req.path)))
+    // Never registered, built per request for the method and path nothing matched
+    pub(crate) fn not_found(method: &str, path: &str) -> AsyncHandler {
+        async fn not_found_fn(mut req: AsyncRequest) -> Result<Response, String> {
+            req.discard_body(MAX_DISCARDED_BODY).await;
+            Ok(Response::error_for(&req.headers, 404, &format!("Resource: {req_path} not found.", req_path = req.path)))
+        }
+
+        AsyncHandler::new(method, path, not_found_fn)
+    }
+
+    // A request that never made it to routing, answered with `err`
+    fn rejected<S: ConnStream>(err: Error, connection: &S) -> AsyncRequest {
+        AsyncRequest::create(
+            "",
+            "",
+            "",
+            Arc::new(AsyncHandler::error(err)),
+            HashMap::new(),
+            Arc::new(DepsMap::default()),
+            Headers::new(),
+            connection.try_clone().unwrap(),
+        )
+    }
+
+    // Writes as much as the connection takes, what is left waits in ConnState::Respond for the next write readiness
+    // A streamed body gets pulled one chunk at a time, the next one only once the connection took the previous one.
+    fn send<S: ConnStream>(mut connection: S, mut pending: PendingResponse) -> Option<(S, ConnState)> {
+        loop {
+            while pending.written != pending.bytes.len() {
+                match connection.write(&pending.bytes[pending.written..]) {
+                    Ok(0) => {
+                        debug!("client hung up");
+                        return Some((connection, ConnState::Flush));
+                    }
+                    Ok(n) => {
+                        pending.written += n;
+                        pending.req.count_written(n);
+                    }
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Some((connection, ConnState::Respond(pending))),
+                    Err(ref err) if err.kind() == io::ErrorKind::InvalidInput => return Some((connection, ConnState::Respond(pending))),
+                    Err(err) => {
+                        debug!("Could not write response, dropping connection. Error: {err}");
+                        return Some((connection, ConnState::Flush));
+                    }
+                }
+            }
+            let Some(stream) = &pending.stream else {
+                break;
+            };
+            let chunked = pending.req.accepts_chunked();
+            pending.bytes = match stream.next_chunk(chunked) {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) => {
+                    let req = &pending.req;
+                    error!(
+                        "Streamed response to {method} {path} failed after {bytes_written} bytes: {e}",
+                        method = req.method(),
+                        path = req.path,
+                        bytes_written = req.bytes_written()
+                    );
+                    return Some((connection, ConnState::Flush));
+                }
+                // without chunks the closed connection is what ends the body
+                None => {
+                    pending.stream = None;
+                    if chunked {
+                        b"0\r\n\r\n".to_vec()
+                    } else {
+                        Vec::new()
+                    }
+                }
+            };
+            pending.written = 0;
         }
+        let req = &pending.req;
+        debug!(
+            "{method} {path} -> {status_code} in {elapsed:?}, {bytes_read} bytes in, {bytes_written} bytes out",
+            method = req.method(),
+            path = req.path,
+            status_code = pending.status_code,
+            elapsed = req.elapsed(),
+            bytes_read = req.bytes_read(),
+            bytes_written = req.bytes_written()
+        );
+        // the connection stays open for the next request (idle ones are up to with_idle_timeout), unless the response told the client it
+        // gets closed. Only now that the response is out, so a connection never has more than one handler running no matter how many
+        // requests it sends at once.
+        if pending.keeps_alive() {
+            return Some((connection, ConnState::Read(Vec::new(), 0)));
+        }
+        Some((connection, ConnState::Flush))
+    }
 
-        AsyncHandler::new("", method, not_found_fn)
+    pub(crate) fn error(err: Error) -> AsyncHandler {
+        AsyncHandler::new("", "", move |_| {
+            let err = err.clone();
+            async move { Err::<Response, Error>(err) }
+        })
     }
 }
 
-impl<T: Send + Sync + 'static, F: Send + 'static> AsyncHandlerFn for T
+// Anything that converts into a response can be returned, e.g. a &str, a ResponseBuilder or a Result of either
+impl<T: Send + Sync + 'static, F: Send + 'static, R> AsyncHandlerFn for T
 where
     T: Fn(AsyncRequest) -> F,
-    F: Future<Output = Result<Response, String>>,
+    F: Future<Output = R>,
+    R: IntoResponse,
 {
-    fn call(&self, args: AsyncRequest) -> Pin<Box<dyn Future<Output = Result<Response, String>> + Send + 'static>> {
-        Box::pin(self(args))
+    fn call(&self, args: AsyncRequest) -> Pin<Box<dyn Future<Output = Result<Response, Error>> + Send + 'static>> {
+        let future = self(args);
+        Box::pin(async move { future.await.into_result() })
     }
 }
 
 pub trait AsyncHandlerFn: Send + Sync + 'static {
-    fn call(&self, args: AsyncRequest) -> Pin<Box<dyn Future<Output = Result<Response, String>> + Send + 'static>>;
+    fn call(&self, args: AsyncRequest) -> Pin<Box<dyn Future<Output = Result<Response, Error>> + Send + 'static>>;
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::futures::catch_unwind::{self, panic_message, CatchUnwind};
     use crate::futures::workers::Workers;
-    use crate::http::async_handler::AsyncHandler;
+    use crate::http::async_handler::{AsyncHandler, ANY_METHOD};
+    use crate::http::headers::Headers;
+    use crate::http::json_body::JsonBodyLimit;
+    use crate::http::limits::Limits;
+    use crate::http::middleware::Next;
+    use crate::http::parse_error::ParseError;
     use crate::http::response::Response;
-    use crate::http::{AsyncRequest, ConnState, ConnStream, Peek, TryClone};
+    use crate::http::response_builder::ResponseBuilder;
+    use crate::http::{AsyncRequest, ConnState, ConnStream, Error, Peek, TryClone};
     use crate::typemap::DepsMap;
+    use crate::utils;
 
-    use std::collections::{HashMap, HashSet};
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
     use std::{
         cmp::min,
         io::{Read, Write},
@@ -211,21 +560,32 @@ mod tests {
     #[derive(Clone)]
     struct FakeConn {
         read_data: Vec<u8>,
-        write_data: Vec<u8>,
+        // shared between clones, like the socket behind a cloned TcpStream
+        write_data: Arc<Mutex<Vec<u8>>>,
+        // data that has not reached the connection yet, see `arrive`
+        pending: VecDeque<Vec<u8>>,
+        // a slow client, see `draining_slowly`
+        max_write: Option<usize>,
+        send_buffer_full: Arc<AtomicBool>,
     }
 
     impl Read for FakeConn {
         fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
             let size: usize = min(self.read_data.len(), buf.len());
             buf[..size].copy_from_slice(&self.read_data[..size]);
+            self.read_data.drain(..size);
             Ok(size)
         }
     }
 
     impl Write for FakeConn {
         fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            self.write_data = Vec::from(buf);
-            Ok(buf.len())
+            let size = min(buf.len(), self.max_write.unwrap_or(buf.len()));
+            if self.max_write.is_some() && self.send_buffer_full.swap(true, Ordering::SeqCst) {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            self.write_data.lock().unwrap().extend_from_slice(&buf[..size]);
+            Ok(size)
         }
 
         fn flush(&mut self) -> std::io::Result<()> {
@@ -235,6 +595,9 @@ mod tests {
 
     impl Peek for FakeConn {
         fn peek(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.read_data.is_empty() && !self.pending.is_empty() {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
             let size: usize = min(self.read_data.len(), buf.len());
             buf[..size].copy_from_slice(&self.read_data[..size]);
             Ok(size)
@@ -251,13 +614,83 @@ mod tests {
 
     impl FakeConn {
         fn new(read_data: &str) -> Self {
+            Self::from_bytes(read_data.as_bytes())
+        }
+
+        fn from_bytes(read_data: &[u8]) -> Self {
+            FakeConn {
+                read_data: read_data.to_vec(),
+                write_data: Arc::default(),
+                pending: VecDeque::new(),
+                max_write: None,
+                send_buffer_full: Arc::default(),
+            }
+        }
+
+        // Only the first fragment is readable right away, the rest arrive one by one
+        fn fragmented(fragments: &[&str]) -> Self {
+            let mut pending: VecDeque<Vec<u8>> = fragments.iter().map(|f| f.as_bytes().to_vec()).collect();
             FakeConn {
-                read_data: read_data.as_bytes().to_vec(),
-                write_data: Vec::default(),
+                read_data: pending.pop_front().unwrap_or_default(),
+                write_data: Arc::default(),
+                pending,
+                max_write: None,
+                send_buffer_full: Arc::default(),
+            }
+        }
+
+        // Takes at most `max_write` bytes per write and then blocks until `drain` gets called
+        fn draining_slowly(mut self, max_write: usize) -> Self {
+            self.max_write = Some(max_write);
+            self
+        }
+
+        fn drain(&self) {
+            self.send_buffer_full.store(false, Ordering::SeqCst);
+        }
+
+        fn written(&self) -> String {
+            String::from_utf8(self.write_data.lock().unwrap().clone()).unwrap()
+        }
+
+        fn arrive(&mut self) {
+            if let Some(fragment) = self.pending.pop_front() {
+                self.read_data.extend(fragment);
             }
         }
     }
 
+    // Drives a request through the Read and then the Write state, returning what has been written to the connection
+    fn read_and_write(conn: FakeConn, handler: AsyncHandler, limits: Limits) -> (FakeConn, ConnState) {
+        read_and_write_routed(conn, HashSet::from([Arc::new(handler)]), limits)
+    }
+
+    fn read_and_write_routed(conn: FakeConn, endpoints: HashSet<Arc<AsyncHandler>>, limits: Limits) -> (FakeConn, ConnState) {
+        read_and_write_with_fallback(conn, endpoints, None, limits)
+    }
+
+    fn read_and_write_with_fallback(conn: FakeConn, endpoints: HashSet<Arc<AsyncHandler>>, fallback: Option<Arc<AsyncHandler>>, limits: Limits) -> (FakeConn, ConnState) {
+        let workers = Workers::new(1);
+        let result = workers.queue_with_result(async move {
+            let (conn, conn_state) = AsyncHandler::handle_async_better(
+                conn,
+                ConnState::Read(Vec::new(), 0),
+                endpoints.clone(),
+                fallback.clone(),
+                Arc::new(DepsMap::default()),
+                limits,
+                Arc::default(),
+                false,
+            )
+            .await
+            .unwrap();
+            AsyncHandler::handle_async_better(conn, conn_state, endpoints, fallback, Arc::new(DepsMap::default()), limits, Arc::default(), false).await
+        });
+        let result = result.unwrap().get().unwrap();
+        workers.poison_all();
+        result
+    }
+
     #[test]
     fn async_can_read_and_match_the_right_handler() {
         async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
@@ -270,59 +703,1492 @@ mod tests {
 
         let handler_clj = handler.clone();
         let conn_clj = conn.clone();
-        let result =
-            workers.queue_with_result(async move { AsyncHandler::handle_async_better(conn_clj, &ConnState::Read(Vec::new(), 0), HashSet::from([handler_clj]), Arc::new(DepsMap::default())).await });
+        let result = workers.queue_with_result(async move {
+            AsyncHandler::handle_async_better(
+                conn_clj,
+                ConnState::Read(Vec::new(), 0),
+                HashSet::from([handler_clj]),
+                None,
+                Arc::new(DepsMap::default()),
+                Limits::default(),
+                Arc::default(),
+                false,
+            )
+            .await
+        });
         let (_conn, conn_state) = result.unwrap().get().unwrap();
         assert_eq!(
             conn_state,
-            ConnState::Write(
-                AsyncRequest::create(
-                    "/some/1",
-                    handler.clone(),
-                    HashMap::from([("id".to_string(), "1".to_string())]),
-                    Arc::new(DepsMap::default()),
-                    HashMap::new(),
-                    Arc::new(Mutex::new(conn)),
-                ),
-                0,
-            )
+            ConnState::Write(AsyncRequest::create(
+                "GET",
+                "/some/1",
+                "HTTP/1.1",
+                handler.clone(),
+                HashMap::from([("id".to_string(), "1".to_string())]),
+                Arc::new(DepsMap::default()),
+                Headers::new(),
+                Arc::new(Mutex::new(conn)),
+            ),)
         );
 
         workers.poison_all()
     }
 
-    //TODO [FL]: add tests for all stages
+    #[test]
+    fn read_assembles_a_request_arriving_in_fragments() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.headers.get("host").unwrap_or_default().to_string()))
+        }
+
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/some/:id", ugh_handler))]);
+        let conn = FakeConn::fragmented(&["GET /some/1 HTTP/1.1\r\nHost: ho", "st:port\r\n\r", "\n"]);
+        let result = workers.queue_with_result(async move {
+            let deps = Arc::new(DepsMap::default());
+            let (conn, first) = AsyncHandler::handle_async_better(conn, ConnState::Read(Vec::new(), 0), endpoints.clone(), None, deps.clone(), Limits::default(), Arc::default(), false)
+                .await
+                .unwrap();
+            // nothing new on the wire, the partial head has to be kept as is
+            let (mut conn, waiting) = AsyncHandler::handle_async_better(conn, first.clone(), endpoints.clone(), None, deps.clone(), Limits::default(), Arc::default(), false)
+                .await
+                .unwrap();
+            conn.arrive();
+            let (mut conn, second) = AsyncHandler::handle_async_better(conn, waiting.clone(), endpoints.clone(), None, deps.clone(), Limits::default(), Arc::default(), false)
+                .await
+                .unwrap();
+            conn.arrive();
+            let (conn, third) = AsyncHandler::handle_async_better(conn, second.clone(), endpoints.clone(), None, deps.clone(), Limits::default(), Arc::default(), false)
+                .await
+                .unwrap();
+            let (conn, _) = AsyncHandler::handle_async_better(conn, third, endpoints, None, deps, Limits::default(), Arc::default(), false)
+                .await
+                .unwrap();
+            (first, waiting, second, conn)
+        });
+        let (first, waiting, second, conn) = result.unwrap().get();
+
+        assert_eq!(first, ConnState::Read(b"GET /some/1 HTTP/1.1\r\nHost: ho".to_vec(), 0));
+        assert_eq!(waiting, first);
+        assert_eq!(second, ConnState::Read(b"GET /some/1 HTTP/1.1\r\nHost: host:port\r\n\r".to_vec(), 0));
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nhost:port");
+
+        workers.poison_all()
+    }
 
     #[test]
-    fn write_can_catch_a_panic() {
+    fn read_reuses_the_buffer_for_a_fragmented_request() {
         async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
-            panic!("panic")
+            Ok(Response::create(200, "".to_string()))
         }
 
         let workers = Workers::new(1);
-        let handler = Arc::new(AsyncHandler::new("GET", "/some/:id", ugh_handler));
-        let conn = FakeConn::new("GET /some/1 HTTP/1.1\r\nHost: host:port\r\nConnection: close\r\n\r\n");
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/some/:id", ugh_handler))]);
+        let conn = FakeConn::fragmented(&["GET /some/1 HTTP/1.1\r\n", "Host: host", ":port\r\n", "\r\n"]);
+        let result = workers.queue_with_result(async move {
+            let deps = Arc::new(DepsMap::default());
+            let mut buffers = Vec::new();
+            let (mut conn, mut conn_state) = AsyncHandler::handle_async_better(conn, ConnState::Read(Vec::new(), 0), endpoints.clone(), None, deps.clone(), Limits::default(), Arc::default(), false)
+                .await
+                .unwrap();
+            while let ConnState::Read(buf, _) = &conn_state {
+                buffers.push((buf.as_ptr() as usize, buf.capacity()));
+                conn.arrive();
+                (conn, conn_state) = AsyncHandler::handle_async_better(conn, conn_state, endpoints.clone(), None, deps.clone(), Limits::default(), Arc::default(), false)
+                    .await
+                    .unwrap();
+            }
+            (buffers, conn_state)
+        });
+        let (buffers, conn_state) = result.unwrap().get();
 
-        let handler_clj = handler.clone();
-        let conn_clj = conn.clone();
-        let write_state = ConnState::Write(
-            AsyncRequest::create(
-                "/some/1",
-                handler.clone(),
-                HashMap::from([("id".to_string(), "1".to_string())]),
-                Arc::new(DepsMap::default()),
-                HashMap::new(),
-                Arc::new(Mutex::new(conn)),
-            ),
-            0,
+        assert_eq!(buffers.len(), 3);
+        assert!(buffers.iter().all(|buffer| *buffer == buffers[0]));
+        assert!(matches!(conn_state, ConnState::Write(_)));
+
+        workers.poison_all()
+    }
+
+    #[test]
+    fn elapsed_covers_the_time_spent_in_the_handler() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            thread::sleep(Duration::from_millis(20));
+            Ok(Response::create(200, x.elapsed().as_millis().to_string()))
+        }
+
+        let conn = FakeConn::new("GET /slow HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/slow", ugh_handler), Limits::default());
+
+        let response = conn.written();
+        let elapsed_ms: u128 = response.split("\r\n\r\n").nth(1).unwrap().parse().unwrap();
+        assert!(elapsed_ms >= 20, "elapsed was {elapsed_ms}ms");
+    }
+
+    #[test]
+    fn handler_with_state_shares_it_across_requests() {
+        async fn count_handler(counter: Arc<AtomicUsize>, _: AsyncRequest) -> Result<Response, String> {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(Response::create(200, count.to_string()))
+        }
+        let counter = Arc::new(AtomicUsize::new(0));
+        let handler = Arc::new(AsyncHandler::from_fn_with_state("GET", "/count", counter.clone(), count_handler));
+
+        for expected in ["1", "2"] {
+            let (conn, _conn_state) = read_and_write_routed(FakeConn::new("GET /count HTTP/1.1\r\nHost: localhost\r\n\r\n"), HashSet::from([handler.clone()]), Limits::default());
+            assert!(conn.written().ends_with(&format!("\r\n\r\n{expected}")));
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn fallback_handles_unmatched_paths() {
+        async fn api_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "api".to_string()))
+        }
+        async fn index_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "<html>index</html>".to_string()))
+        }
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/api", api_handler))]);
+        let fallback = Some(Arc::new(AsyncHandler::new("GET", "/", index_handler)));
+
+        let (conn, _conn_state) = read_and_write_with_fallback(
+            FakeConn::new("GET /app/settings HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            endpoints.clone(),
+            fallback.clone(),
+            Limits::default(),
         );
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 18\r\n\r\n<html>index</html>");
+
+        let (conn, _conn_state) = read_and_write_with_fallback(FakeConn::new("GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n"), endpoints, fallback, Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\napi");
+    }
+
+    #[test]
+    fn fallback_reads_bodies_within_the_configured_limits() {
+        async fn upload_handler(mut req: AsyncRequest) -> Result<String, Error> {
+            req.body().await
+        }
+        let fallback = Some(Arc::new(AsyncHandler::new(ANY_METHOD, "/", upload_handler)));
+        let limits = Limits {
+            max_body_size: 8,
+            ..Limits::default()
+        };
+
+        let upload = FakeConn::new("POST /anywhere HTTP/1.1\r\nHost: localhost\r\nContent-Length: 12\r\n\r\nhello, world");
+        let (conn, _conn_state) = read_and_write_with_fallback(upload, HashSet::new(), fallback, limits);
+
+        assert_eq!(conn.written(), "HTTP/1.1 413 Content Too Large\r\nConnection: close\r\nContent-Length: 14\r\n\r\nBody too large");
+    }
+
+    #[test]
+    fn trace_and_connect_need_a_route_of_their_own() {
+        async fn any_handler(_: AsyncRequest) -> &'static str {
+            "any"
+        }
+        async fn trace_handler(_: AsyncRequest) -> &'static str {
+            "trace"
+        }
+        let endpoints = HashSet::from([
+            Arc::new(AsyncHandler::new(ANY_METHOD, "/a", any_handler)),
+            Arc::new(AsyncHandler::new("TRACE", "/traced", trace_handler)),
+        ]);
+        let fallback = Some(Arc::new(AsyncHandler::new(ANY_METHOD, "/", any_handler)));
+        let status_of = |request: &str| {
+            let (conn, _conn_state) = read_and_write_with_fallback(FakeConn::new(request), endpoints.clone(), fallback.clone(), Limits::default());
+            conn.written().lines().next().unwrap().to_string()
+        };
+
+        assert_eq!(status_of("TRACE /a HTTP/1.1\r\nHost: localhost\r\n\r\n"), "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(status_of("TRACE /elsewhere HTTP/1.1\r\nHost: localhost\r\n\r\n"), "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(status_of("CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\n\r\n"), "HTTP/1.1 405 Method Not Allowed");
+        assert_eq!(status_of("GET /a HTTP/1.1\r\nHost: localhost\r\n\r\n"), "HTTP/1.1 200 OK");
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new("TRACE /traced HTTP/1.1\r\nHost: localhost\r\n\r\n"), endpoints, Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\ntrace");
+    }
+
+    #[test]
+    fn raw_response_is_written_without_an_added_content_length() {
+        async fn ugh_handler(_: AsyncRequest) -> Response {
+            let mut headers = Headers::new();
+            headers.insert("Transfer-Encoding", "chunked");
+            Response::raw(200, headers, "5\r\nhello\r\n0\r\n\r\n".to_string())
+        }
+        let handler = AsyncHandler::new("GET", "/stream", ugh_handler);
+
+        let (conn, _conn_state) = read_and_write(FakeConn::new("GET /stream HTTP/1.1\r\nHost: localhost\r\n\r\n"), handler, Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn empty_method_handler_does_not_shadow_real_routes() {
+        async fn real_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "real".to_string()))
+        }
+        async fn shadow_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "shadow".to_string()))
+        }
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/users", real_handler)), Arc::new(AsyncHandler::new("", "/users", shadow_handler))]);
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new("GET /users HTTP/1.1\r\nHost: localhost\r\n\r\n"), endpoints.clone(), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nreal");
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new(" /users HTTP/1.1\r\nHost: localhost\r\n\r\n"), endpoints, Limits::default());
+        assert!(conn.written().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn vhost_routes_win_for_their_host() {
+        async fn api_handler(_: AsyncRequest) -> &'static str {
+            "api"
+        }
+        async fn any_handler(_: AsyncRequest) -> &'static str {
+            "any"
+        }
+        let endpoints = HashSet::from([
+            Arc::new(AsyncHandler::new("GET", "/status", api_handler).for_host("api.example.com")),
+            Arc::new(AsyncHandler::new("GET", "/status", any_handler)),
+        ]);
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new("GET /status HTTP/1.1\r\nHost: API.example.com:8080\r\n\r\n"), endpoints.clone(), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\napi");
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new("GET /status HTTP/1.1\r\nHost: www.example.com\r\n\r\n"), endpoints, Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nany");
+    }
+
+    #[test]
+    fn http_1_1_request_without_host_is_rejected_with_400() {
+        async fn ugh_handler(_: AsyncRequest) -> &'static str {
+            "ugh"
+        }
+
+        let (conn, _conn_state) = read_and_write(FakeConn::new("GET /status HTTP/1.1\r\n\r\n"), AsyncHandler::new("GET", "/status", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 19\r\n\r\nMissing Host header");
+
+        let (conn, _conn_state) = read_and_write(FakeConn::new("GET /status HTTP/1.0\r\n\r\n"), AsyncHandler::new("GET", "/status", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 3\r\n\r\nugh");
+    }
+
+    #[test]
+    fn query_constraints_pick_the_route() {
+        async fn a_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, format!("a {id}", id = x.path_params.get("id").unwrap())))
+        }
+        async fn b_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "b".to_string()))
+        }
+        let endpoints = HashSet::from([
+            Arc::new(AsyncHandler::new("POST", "/op/:id", a_handler).with_query_required("action", "a")),
+            Arc::new(AsyncHandler::new("POST", "/op/:id", b_handler).with_query_required("action", "b")),
+        ]);
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new("POST /op/1?action=a HTTP/1.1\r\nHost: localhost\r\n\r\n"), endpoints.clone(), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\na 1");
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new("POST /op/1?debug&action=b HTTP/1.1\r\nHost: localhost\r\n\r\n"), endpoints.clone(), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb");
+
+        let (conn, _conn_state) = read_and_write_routed(FakeConn::new("POST /op/1?action=c HTTP/1.1\r\nHost: localhost\r\n\r\n"), endpoints, Limits::default());
+        assert!(conn.written().starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn built_in_errors_follow_the_accepted_format() {
+        async fn a_handler(_: AsyncRequest) -> &'static str {
+            "a"
+        }
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/a", a_handler))]);
+        let written_for = |request: &str| {
+            let (conn, _conn_state) = read_and_write_routed(FakeConn::new(request), endpoints.clone(), Limits::default());
+            conn.written()
+        };
+
+        let json = written_for("GET /missing HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\n\r\n");
+        let (head, body) = json.split_once("\r\n\r\n").unwrap();
+        assert_eq!(head, "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: 66");
+        assert_eq!(serde_json::from_str::<serde_json::Value>(body).unwrap()["error"]["message"], "Resource: /missing not found.");
+
+        let html = written_for("GET /missing HTTP/1.1\r\nHost: localhost\r\nAccept: text/html,*/*;q=0.8\r\n\r\n");
+        assert!(html.starts_with("HTTP/1.1 404 Not Found\r\nContent-Type: text/html; charset=utf-8\r\n"));
+        assert!(html.ends_with("<h1>404 Not Found</h1><p>Resource: /missing not found.</p></body></html>"));
+
+        let not_allowed = written_for("TRACE /a HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\n\r\n");
+        let body = not_allowed.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(serde_json::from_str::<serde_json::Value>(body).unwrap()["error"]["status"], 405);
+
+        // no preference keeps the plain text
+        assert!(written_for("GET /missing HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n").ends_with("Content-Length: 29\r\n\r\nResource: /missing not found."));
+    }
+
+    #[test]
+    fn overlong_uri_is_rejected_with_414() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, String::new()))
+        }
+
+        let path = format!("/{}", "a".repeat(2 * 1024 * 1024));
+        let conn = FakeConn::new(&format!("GET {path} HTTP/1.1\r\nHost: host:port\r\n\r\n"));
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/:long", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 414 URI Too Long\r\nConnection: close\r\nContent-Length: 12\r\n\r\nURI Too Long");
+
+        let conn = FakeConn::new("GET /aaaaaaaaaa HTTP/1.1\r\nHost: host:port\r\n\r\n");
+        let limits = Limits {
+            max_uri_length: 10,
+            ..Limits::default()
+        };
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/:long", ugh_handler), limits);
+        assert!(conn.written().starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    }
+
+    #[test]
+    fn expect_continue_gets_100_once_the_body_is_read() {
+        async fn upload_handler(mut req: AsyncRequest) -> Result<String, Error> {
+            req.body().await
+        }
+        async fn ignoring_handler(_: AsyncRequest) -> &'static str {
+            "ignored"
+        }
+        let endpoints = HashSet::from([
+            Arc::new(AsyncHandler::new("POST", "/upload", upload_handler)),
+            Arc::new(AsyncHandler::new("POST", "/ignore", ignoring_handler)),
+        ]);
+        let written_for = |request: &str| {
+            let (conn, _conn_state) = read_and_write_routed(FakeConn::new(request), endpoints.clone(), Limits::default());
+            conn.written()
+        };
 
-        let result = workers.queue_with_result(async move { AsyncHandler::handle_async_better(conn_clj, &write_state, HashSet::from([handler_clj]), Arc::new(DepsMap::default())).await });
-        let (conn, _conn_state) = result.unwrap().get().unwrap();
         assert_eq!(
-            String::from_utf8(conn.write_data).unwrap(),
-            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 28\r\n\r\nInternal server error\n:panic"
+            written_for("POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello"),
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello"
         );
+        assert_eq!(
+            written_for("POST /ignore HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n"),
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 7\r\n\r\nignored"
+        );
+        assert!(written_for("POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: something-else\r\nContent-Length: 5\r\n\r\n").starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+    }
+
+    #[test]
+    fn expect_continue_with_an_oversized_body_is_rejected_up_front() {
+        async fn upload_handler(mut req: AsyncRequest) -> Result<String, Error> {
+            req.body().await
+        }
+        let limits = Limits {
+            max_body_size: 1024,
+            ..Limits::default()
+        };
+
+        let conn = FakeConn::new("POST /upload HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 1025\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/upload", upload_handler), limits);
+
+        assert_eq!(conn.written(), "HTTP/1.1 413 Content Too Large\r\nConnection: close\r\nContent-Length: 14\r\n\r\nBody too large");
+        assert_eq!(conn_state, ConnState::Flush);
+    }
+
+    #[test]
+    fn oversized_head_is_answered_with_431() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, String::new()))
+        }
+
+        // short lines only, the head size is not raised to make room for a long one
+        let limits = Limits {
+            max_uri_length: 1024,
+            max_header_line_length: 1024,
+            ..Limits::default()
+        };
+        let headers = (0..200).map(|i| format!("X-Filler-{i}: {}\r\n", "f".repeat(50))).collect::<String>();
+        let conn = FakeConn::new(&format!("GET /a HTTP/1.1\r\nHost: host:port\r\n{headers}\r\n"));
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/a", ugh_handler), limits);
+
+        assert_eq!(
+            conn.written(),
+            "HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\nContent-Length: 25\r\n\r\nRequest headers too large"
+        );
+        assert_eq!(conn_state, ConnState::Flush);
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.path))
+        }
+
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/some/:id", ugh_handler))]);
+        let conn = FakeConn::new("GET /some/1 HTTP/1.1\r\nHost: localhost\r\n\r\nGET /some/2 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let result = workers.queue_with_result(async move {
+            let mut state = (conn, ConnState::Read(Vec::new(), 0));
+            let mut states = Vec::new();
+            // the fifth read finds the connection closed by the client
+            for _ in 0..5 {
+                state = AsyncHandler::handle_async_better(state.0, state.1, endpoints.clone(), None, Arc::new(DepsMap::default()), Limits::default(), Arc::default(), false)
+                    .await
+                    .unwrap();
+                states.push(matches!(state.1, ConnState::Read(..)));
+            }
+            (state, states)
+        });
+        let ((conn, conn_state), states) = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(states, vec![false, true, false, true, false]);
+        assert_eq!(conn_state, ConnState::Flush);
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\n/some/1HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\n/some/2");
+    }
+
+    #[test]
+    fn ambiguous_body_length_is_rejected_before_anything_gets_pipelined() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.path))
+        }
+
+        // a proxy going by the first length forwards the smuggled request as the body
+        let smuggled = "GET /some/2 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        for framing in ["Content-Length: 41\r\nContent-Length: 0", "Content-Length: 41\r\nTransfer-Encoding: chunked"] {
+            let conn = FakeConn::new(&format!("POST /some/1 HTTP/1.1\r\nHost: localhost\r\n{framing}\r\n\r\n{smuggled}"));
+            let (conn, conn_state) = read_and_write(conn, AsyncHandler::new(ANY_METHOD, "/some/:id", ugh_handler), Limits::default());
+
+            assert_eq!(conn_state, ConnState::Flush);
+            assert!(conn.written().starts_with("HTTP/1.1 400 Bad Request\r\nConnection: close\r\n"));
+            assert!(!conn.written().contains("/some/2"));
+        }
+    }
+
+    #[test]
+    fn connection_close_ends_the_connection_after_one_response() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.path))
+        }
+
+        let conn = FakeConn::new("GET /some/1 HTTP/1.1\r\nHost: localhost\r\nConnection: Close\r\n\r\nGET /some/2 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/some/:id", ugh_handler), Limits::default());
+
+        assert_eq!(conn_state, ConnState::Flush);
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 7\r\n\r\n/some/1");
+    }
+
+    #[test]
+    fn middleware_can_act_on_route_meta() {
+        async fn auth(req: AsyncRequest, next: Next) -> Result<Response, Error> {
+            if req.handler.meta("requires_auth") == Some("true") && req.headers.authorization().is_none() {
+                return Err(Error::new(401, "Unauthorized"));
+            }
+            next.run(req).await
+        }
+        async fn ugh_handler(_: AsyncRequest) -> &'static str {
+            "ok"
+        }
+        let endpoints = HashSet::from([
+            Arc::new(AsyncHandler::new("GET", "/public", ugh_handler).behind(&[Arc::new(auth)])),
+            Arc::new(AsyncHandler::new("GET", "/private", ugh_handler).with_meta("requires_auth", "true").behind(&[Arc::new(auth)])),
+        ]);
+        let status_of = |request: &str| {
+            let (conn, _conn_state) = read_and_write_routed(FakeConn::new(request), endpoints.clone(), Limits::default());
+            conn.written().lines().next().unwrap().to_string()
+        };
+
+        assert_eq!(status_of("GET /public HTTP/1.1\r\nHost: localhost\r\n\r\n"), "HTTP/1.1 200 OK");
+        assert_eq!(status_of("GET /private HTTP/1.1\r\nHost: localhost\r\n\r\n"), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(status_of("GET /private HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer x\r\n\r\n"), "HTTP/1.1 200 OK");
+    }
+
+    #[test]
+    fn default_headers_apply_unless_the_handler_sets_them() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, String::new()).with_header("x-frame-options", "SAMEORIGIN"))
+        }
+
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/page", ugh_handler))]);
+        let default_headers = Arc::new(Headers::from_lines(["X-Content-Type-Options: nosniff", "X-Frame-Options: DENY"]));
+        let conn = FakeConn::new("GET /page HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let result = workers.queue_with_result(async move {
+            let deps = Arc::new(DepsMap::default());
+            let (conn, conn_state) = AsyncHandler::handle_async_better(
+                conn,
+                ConnState::Read(Vec::new(), 0),
+                endpoints.clone(),
+                None,
+                deps.clone(),
+                Limits::default(),
+                default_headers.clone(),
+                false,
+            )
+            .await
+            .unwrap();
+            AsyncHandler::handle_async_better(conn, conn_state, endpoints, None, deps, Limits::default(), default_headers, false)
+                .await
+                .unwrap()
+        });
+        let (conn, _conn_state) = result.unwrap().get();
+        workers.poison_all();
+
+        let written = conn.written();
+        assert!(written.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+        assert!(written.contains("\r\nx-frame-options: SAMEORIGIN\r\n"));
+        assert!(!written.contains("DENY"));
+    }
+
+    #[test]
+    fn handler_error_closes_the_connection() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Err("database is gone".to_string())
+        }
+
+        // the request waiting behind the failed one is not read anymore
+        let conn = FakeConn::new("GET /users HTTP/1.1\r\nHost: localhost\r\n\r\nGET /users HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/users", ugh_handler), Limits::default());
+
+        assert_eq!(conn_state, ConnState::Flush);
+        assert!(conn.written().starts_with("HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn closing_drains_until_the_client_closes() {
+        let workers = Workers::new(1);
+        let conn = FakeConn::new("leftover upload bytes");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let result = workers.queue_with_result(async move {
+            AsyncHandler::handle_async_better(
+                conn,
+                ConnState::Closing(deadline),
+                HashSet::new(),
+                None,
+                Arc::new(DepsMap::default()),
+                Limits::default(),
+                Arc::default(),
+                false,
+            )
+            .await
+            .unwrap()
+        });
+        let (conn, conn_state) = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(conn_state, ConnState::Flush);
+        assert!(conn.read_data.is_empty());
+        assert!(conn.written().is_empty());
+    }
+
+    #[test]
+    fn bytes_after_an_empty_body_are_read_as_the_next_request() {
+        async fn length_handler(mut req: AsyncRequest) -> Result<String, Error> {
+            Ok(req.body_bytes().await?.len().to_string())
+        }
+
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("POST", "/upload", length_handler))]);
+        let conn = FakeConn::new("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\nJUNK\r\n\r\n");
+        let result = workers.queue_with_result(async move {
+            let mut state = (conn, ConnState::Read(Vec::new(), 0));
+            for _ in 0..4 {
+                state = AsyncHandler::handle_async_better(state.0, state.1, endpoints.clone(), None, Arc::new(DepsMap::default()), Limits::default(), Arc::default(), false)
+                    .await
+                    .unwrap();
+            }
+            state
+        });
+        let (conn, conn_state) = result.unwrap().get();
+        workers.poison_all();
+
+        // the empty body gets answered on its own, the junk is rejected as a request of its own instead of ending up in the body
+        assert_eq!(
+            conn.written(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\n0HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 22\r\n\r\nMalformed request line"
+        );
+        assert_eq!(conn_state, ConnState::Flush);
+    }
+
+    #[test]
+    fn content_length_with_a_sign_is_rejected() {
+        async fn length_handler(mut req: AsyncRequest) -> Result<String, Error> {
+            Ok(req.body_bytes().await?.len().to_string())
+        }
+
+        let conn = FakeConn::new("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: +0\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/upload", length_handler), Limits::default());
+
+        assert!(conn.written().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(conn_state, ConnState::Flush);
+    }
+
+    fn gzipped_upload(body: &[u8]) -> FakeConn {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        let body = encoder.finish().unwrap();
+        let head = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: gzip\r\nContent-Length: {length}\r\n\r\n",
+            length = body.len()
+        );
+        FakeConn::from_bytes(&[head.as_bytes(), &body].concat())
+    }
+
+    #[test]
+    fn gzip_encoded_body_reaches_the_handler_decoded() {
+        async fn upload_handler(mut req: AsyncRequest) -> Result<String, Error> {
+            req.body().await
+        }
+
+        let (conn, _conn_state) = read_and_write(gzipped_upload(b"hello, compressed world"), AsyncHandler::new("POST", "/upload", upload_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 23\r\n\r\nhello, compressed world");
+
+        let conn = FakeConn::new("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: br\r\nContent-Length: 5\r\n\r\nhello");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/upload", upload_handler), Limits::default());
+        assert!(conn.written().starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+    }
+
+    #[test]
+    fn decompression_bomb_is_stopped_at_the_body_limit() {
+        async fn upload_handler(mut req: AsyncRequest) -> Result<String, Error> {
+            Ok(req.body_bytes().await?.len().to_string())
+        }
+        let limits = Limits {
+            max_body_size: 64 * 1024,
+            ..Limits::default()
+        };
+
+        // a few kB on the wire, 8 MB once inflated
+        let bomb = gzipped_upload(&vec![b'a'; 8 * 1024 * 1024]);
+        assert!(bomb.read_data.len() < limits.max_body_size);
+        let (conn, _conn_state) = read_and_write(bomb, AsyncHandler::new("POST", "/upload", upload_handler), limits);
+
+        assert_eq!(conn.written(), "HTTP/1.1 413 Content Too Large\r\nConnection: close\r\nContent-Length: 14\r\n\r\nBody too large");
+    }
+
+    #[test]
+    fn request_with_a_body_is_not_pipelined() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, String::new()))
+        }
+
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 18\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (_conn, conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/users", ugh_handler), Limits::default());
+
+        assert_eq!(conn_state, ConnState::Flush);
+    }
+
+    #[test]
+    fn target_that_is_not_utf8_is_rejected_with_400() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, String::new()))
+        }
+
+        let overlong = FakeConn::new("GET /files/a%C0%80 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(overlong, AsyncHandler::new("GET", "/files/:name", ugh_handler), Limits::default());
+        assert_eq!(
+            conn.written(),
+            "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 33\r\n\r\nRequest target is not valid UTF-8"
+        );
+
+        let raw = FakeConn::from_bytes(b"GET /files/a\xff HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(raw, AsyncHandler::new("GET", "/files/:name", ugh_handler), Limits::default());
+        assert!(conn.written().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn handler_can_use_question_mark_on_io_errors() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, Error> {
+            let contents = std::fs::read_to_string("/definitely/not/here")?;
+            Ok(Response::create(200, contents))
+        }
+
+        let conn = FakeConn::new("GET /file HTTP/1.1\r\nHost: host:port\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/file", ugh_handler), Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nNot Found");
+        // a client error leaves the connection open for the next request
+        assert_eq!(conn_state, ConnState::Read(Vec::new(), 0));
+    }
+
+    #[test]
+    fn consuming_handler_rejects_other_content_types_with_415() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "{}".to_string()))
+        }
+
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi");
+        let handler = AsyncHandler::new("POST", "/users", ugh_handler).consumes("application/json").produces("application/json");
+        let (conn, _conn_state) = read_and_write(conn, handler, Limits::default());
+
+        assert!(conn.written().starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+    }
+
+    #[test]
+    fn unsupported_media_type_request_keeps_the_deps() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "{}".to_string()))
+        }
+
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("POST", "/users", ugh_handler).consumes("application/json"))]);
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi");
+        let mut deps_map = DepsMap::default();
+        deps_map.insert("db".to_string());
+        let result = workers.queue_with_result(async move {
+            AsyncHandler::handle_async_better(conn, ConnState::Read(Vec::new(), 0), endpoints, None, Arc::new(deps_map), Limits::default(), Arc::default(), false).await
+        });
+        let (_conn, conn_state) = result.unwrap().get().unwrap();
+        workers.poison_all();
+
+        let ConnState::Write(req) = conn_state else {
+            panic!("expected the 415 to be dispatched, got {conn_state}");
+        };
+        assert_eq!(req.deps.get::<String>().map(String::as_str), Some("db"));
+    }
+
+    #[test]
+    fn producing_handler_sets_the_response_content_type() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "{}".to_string()))
+        }
+
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json; charset=utf-8\r\nContent-Length: 2\r\n\r\n{}");
+        let handler = AsyncHandler::new("POST", "/users", ugh_handler).consumes("application/json").produces("application/json");
+        let (conn, _conn_state) = read_and_write(conn, handler, Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}");
+    }
+
+    #[test]
+    fn wildcard_method_handles_any_method_unless_an_exact_route_exists() {
+        async fn proxy_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, format!("proxy {method}", method = x.method())))
+        }
+        async fn special_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, "special".to_string()))
+        }
+        let endpoints = || {
+            HashSet::from([
+                Arc::new(AsyncHandler::new(ANY_METHOD, "/proxy/:rest", proxy_handler)),
+                Arc::new(AsyncHandler::new("GET", "/proxy/special", special_handler)),
+            ])
+        };
+        let body_of = |request: &str| {
+            let (conn, _conn_state) = read_and_write_routed(FakeConn::new(request), endpoints(), Limits::default());
+            conn.written().split("\r\n\r\n").nth(1).unwrap().to_string()
+        };
+
+        assert_eq!(body_of("GET /proxy/anything HTTP/1.1\r\nHost: localhost\r\n\r\n"), "proxy GET");
+        assert_eq!(body_of("DELETE /proxy/special HTTP/1.1\r\nHost: localhost\r\n\r\n"), "proxy DELETE");
+        assert_eq!(body_of("GET /proxy/special HTTP/1.1\r\nHost: localhost\r\n\r\n"), "special");
+    }
+
+    //TODO [FL]: add tests for all stages
+
+    #[test]
+    fn write_can_catch_a_panic() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            panic!("panic")
+        }
+
+        let workers = Workers::new(1);
+        let handler = Arc::new(AsyncHandler::new("GET", "/some/:id", ugh_handler));
+        let conn = FakeConn::new("GET /some/1 HTTP/1.1\r\nHost: host:port\r\nConnection: close\r\n\r\n");
+
+        let handler_clj = handler.clone();
+        let conn_clj = conn.clone();
+        let write_state = ConnState::Write(AsyncRequest::create(
+            "GET",
+            "/some/1",
+            "HTTP/1.1",
+            handler.clone(),
+            HashMap::from([("id".to_string(), "1".to_string())]),
+            Arc::new(DepsMap::default()),
+            Headers::new(),
+            Arc::new(Mutex::new(conn)),
+        ));
+
+        let result = workers.queue_with_result(async move {
+            AsyncHandler::handle_async_better(
+                conn_clj,
+                write_state,
+                HashSet::from([handler_clj]),
+                None,
+                Arc::new(DepsMap::default()),
+                Limits::default(),
+                Arc::default(),
+                false,
+            )
+            .await
+        });
+        let (conn, _conn_state) = result.unwrap().get().unwrap();
+        assert_eq!(
+            conn.written(),
+            "HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\nContent-Length: 28\r\n\r\nInternal server error\n:panic"
+        );
+    }
+
+    #[test]
+    fn write_propagates_a_panic_when_asked_to() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            panic!("panic")
+        }
+
+        let workers = Workers::new(1);
+        let handler = Arc::new(AsyncHandler::new("GET", "/some/:id", ugh_handler));
+        let conn = FakeConn::new("");
+        let write_state = ConnState::Write(AsyncRequest::create(
+            "GET",
+            "/some/1",
+            "HTTP/1.1",
+            handler.clone(),
+            HashMap::new(),
+            Arc::new(DepsMap::default()),
+            Headers::new(),
+            Arc::new(Mutex::new(conn.clone())),
+        ));
+
+        let result = workers.queue_with_result(async move {
+            CatchUnwind::new(AsyncHandler::handle_async_better(
+                conn,
+                write_state,
+                HashSet::from([handler]),
+                None,
+                Arc::new(DepsMap::default()),
+                Limits::default(),
+                Arc::default(),
+                true,
+            ))
+            .await
+            .map(|_| ())
+            .map_err(|e| panic_message(e.as_ref()).map(str::to_string))
+        });
+
+        assert_eq!(result.unwrap().get(), Err(Some("panic".to_string())));
+        workers.poison_all()
+    }
+
+    #[test]
+    fn read_rejects_non_utf8_header_with_bad_request() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.path))
+        }
+
+        let conn = FakeConn::from_bytes(b"GET /some/1 HTTP/1.1\r\nHost: localhost\r\nX-Opaque: caf\xe9\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/some/:id", ugh_handler), Limits::default());
+        assert_eq!(conn_state, ConnState::Flush);
+        assert_eq!(conn.written(), "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 25\r\n\r\nHeader is not valid UTF-8");
+    }
+
+    #[test]
+    fn read_rejects_header_line_without_colon_with_bad_request() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.path))
+        }
+
+        let conn = FakeConn::new("GET /some/1 HTTP/1.1\r\nHost: host:port\r\nnot a header\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/some/:id", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 21\r\n\r\nMalformed header line");
+    }
+
+    #[test]
+    fn read_rejects_too_many_headers_with_431() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.path))
+        }
+
+        let conn = FakeConn::new("GET /some/1 HTTP/1.1\r\nHost: host:port\r\nA: 1\r\nB: 2\r\n\r\n");
+        let limits = Limits {
+            max_header_count: 2,
+            ..Limits::default()
+        };
+
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/some/:id", ugh_handler), limits);
+        assert_eq!(
+            conn.written(),
+            "HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\nContent-Length: 16\r\n\r\nToo many headers"
+        );
+    }
+
+    #[test]
+    fn handler_can_read_method_and_version() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, format!("{} {}", x.method(), x.version())))
+        }
+
+        let conn = FakeConn::new("DELETE /some/1 HTTP/1.0\r\nHost: host:port\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("DELETE", "/some/:id", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 15\r\n\r\nDELETE HTTP/1.0");
+    }
+
+    #[test]
+    fn matched_route_reports_the_route_pattern() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, String> {
+            Ok(Response::create(200, x.matched_route().unwrap_or_default().to_string()))
+        }
+
+        let conn = FakeConn::new("GET /users/42 HTTP/1.1\r\nHost: host:port\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/users/:id", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n/users/:id");
+    }
+
+    #[test]
+    fn handler_can_return_a_response_builder() {
+        async fn ugh_handler(_: AsyncRequest) -> Result<ResponseBuilder, String> {
+            Ok(ResponseBuilder::new(201).header("Location", "/users/1").body("created"))
+        }
+
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: host:port\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/users", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 201 Created\r\nLocation: /users/1\r\nContent-Length: 7\r\n\r\ncreated");
+    }
+
+    #[test]
+    fn chunked_response_to_http_1_0_is_ended_by_closing() {
+        async fn ugh_handler(_: AsyncRequest) -> ResponseBuilder {
+            ResponseBuilder::new(200).body("page").trailer("X-Checksum", "abc")
+        }
+
+        let conn = FakeConn::new("GET /page HTTP/1.0\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/page", ugh_handler), Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\npage");
+        assert_eq!(conn_state, ConnState::Flush);
+    }
+
+    #[test]
+    fn streaming_response_goes_out_chunked() {
+        async fn ugh_handler(_: AsyncRequest) -> Response {
+            Response::streaming(200, ["alpha", "", "beta"].into_iter().map(String::from))
+        }
+
+        let conn = FakeConn::new("GET /stream HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/stream", ugh_handler), Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nalpha\r\n4\r\nbeta\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn streaming_response_to_http_1_0_is_ended_by_closing() {
+        async fn ugh_handler(_: AsyncRequest) -> Response {
+            Response::streaming(200, ["alpha", "", "beta"].into_iter().map(String::from))
+        }
+
+        let conn = FakeConn::new("GET /stream HTTP/1.0\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/stream", ugh_handler), Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nalphabeta");
+        assert_eq!(conn_state, ConnState::Flush);
+    }
+
+    #[test]
+    fn client_gone_halfway_through_the_head_closes_without_an_answer() {
+        async fn ugh_handler(_: AsyncRequest) -> &'static str {
+            "never"
+        }
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/a", ugh_handler))]);
+        let result = workers.queue_with_result(async move {
+            let mut step = (FakeConn::new("GET /a HTTP/1.1\r\nHo"), ConnState::Read(Vec::new(), 0));
+            // the first read takes what has arrived, the second one finds the connection at EOF
+            for _ in 0..2 {
+                let (conn, conn_state) = step;
+                step = AsyncHandler::handle_async_better(conn, conn_state, endpoints.clone(), None, Arc::new(DepsMap::default()), Limits::default(), Arc::default(), false)
+                    .await
+                    .unwrap();
+            }
+            step
+        });
+        let (conn, conn_state) = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(conn_state, ConnState::Flush);
+        assert_eq!(conn.written(), "");
+    }
+
+    #[test]
+    fn failing_stream_is_cut_off_without_the_last_chunk() {
+        capture_log();
+        async fn ugh_handler(_: AsyncRequest) -> Response {
+            Response::try_streaming(
+                200,
+                [Ok("alpha".to_string()), Ok("beta".to_string()), Err("cursor gone".to_string()), Ok("never".to_string())].into_iter(),
+            )
+        }
+
+        let conn = FakeConn::new("GET /failing-stream HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/failing-stream", ugh_handler), Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nalpha\r\n4\r\nbeta\r\n");
+        assert_eq!(conn_state, ConnState::Flush);
+        assert_eq!(
+            captured_log("/failing-stream failed"),
+            vec!["Streamed response to GET /failing-stream failed after 66 bytes: cursor gone"]
+        );
+    }
+
+    #[test]
+    fn streaming_response_is_pulled_as_a_slow_client_takes_it() {
+        static PULLED: AtomicUsize = AtomicUsize::new(0);
+        async fn ugh_handler(_: AsyncRequest) -> Response {
+            Response::streaming(
+                200,
+                (0..100).map(|_| {
+                    PULLED.fetch_add(1, Ordering::SeqCst);
+                    "x".repeat(1000)
+                }),
+            )
+        }
+
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/stream", ugh_handler))]);
+        let conn = FakeConn::new("GET /stream HTTP/1.1\r\nHost: localhost\r\n\r\n").draining_slowly(8 * 1024);
+        let result = workers.queue_with_result(async move {
+            let mut step = (conn, ConnState::Read(Vec::new(), 0));
+            let mut pulled_while_responding = Vec::new();
+            while step.1 != ConnState::Flush {
+                if matches!(step.1, ConnState::Respond(_)) {
+                    pulled_while_responding.push(PULLED.load(Ordering::SeqCst));
+                }
+                step.0.drain();
+                let (conn, conn_state) = step;
+                step = AsyncHandler::handle_async_better(conn, conn_state, endpoints.clone(), None, Arc::new(DepsMap::default()), Limits::default(), Arc::default(), false)
+                    .await
+                    .unwrap();
+            }
+            (step.0, pulled_while_responding)
+        });
+        let (conn, pulled_while_responding) = result.unwrap().get();
+        workers.poison_all();
+
+        assert!(pulled_while_responding.first().is_some_and(|&pulled| pulled < 20), "{pulled_while_responding:?}");
+        assert_eq!(PULLED.load(Ordering::SeqCst), 100);
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let chunk = format!("3e8\r\n{}\r\n", "x".repeat(1000));
+        assert_eq!(conn.written(), format!("{head}{chunks}0\r\n\r\n", chunks = chunk.repeat(100)));
+    }
+
+    #[test]
+    fn large_response_to_a_slow_client_resumes_where_it_left_off() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::create(200, "0123456789".repeat(400_000)))
+        }
+
+        let workers = Workers::new(1);
+        let endpoints = HashSet::from([Arc::new(AsyncHandler::new("GET", "/large", ugh_handler))]);
+        let conn = FakeConn::new("GET /large HTTP/1.1\r\nHost: localhost\r\n\r\n").draining_slowly(64 * 1024);
+        let result = workers.queue_with_result(async move {
+            let mut step = (conn, ConnState::Read(Vec::new(), 0));
+            let mut resumed = 0;
+            while step.1 != ConnState::Flush {
+                if matches!(step.1, ConnState::Respond(_)) {
+                    resumed += 1;
+                }
+                step.0.drain();
+                let (conn, conn_state) = step;
+                step = AsyncHandler::handle_async_better(conn, conn_state, endpoints.clone(), None, Arc::new(DepsMap::default()), Limits::default(), Arc::default(), false)
+                    .await
+                    .unwrap();
+            }
+            (step.0, resumed)
+        });
+        let (conn, resumed) = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert!(resumed > 60, "{resumed}");
+        let written = conn.written();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 4000000\r\n\r\n0123456789"));
+        assert_eq!(written.len(), "HTTP/1.1 200 OK\r\nContent-Length: 4000000\r\n\r\n".len() + 4_000_000);
+        assert!(written.ends_with(&"0123456789".repeat(10)));
+    }
+
+    #[test]
+    fn request_counts_bytes_read_and_written() {
+        let seen = Arc::new(Mutex::new(None));
+        let seen_by_handler = seen.clone();
+        let handler = AsyncHandler::new("POST", "/upload", move |mut req: AsyncRequest| {
+            let seen = seen_by_handler.clone();
+            async move {
+                let body = req.body().await?;
+                *seen.lock().unwrap() = Some(req);
+                Ok::<_, Error>(Response::create(200, body.to_uppercase()))
+            }
+        });
+
+        let request = "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let (conn, _conn_state) = read_and_write(FakeConn::new(request), handler, Limits::default());
+
+        let req = seen.lock().unwrap().take().unwrap();
+        assert_eq!(req.bytes_read(), request.len() as u64);
+        assert_eq!(req.bytes_written(), conn.written().len() as u64);
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\nHELLO");
+    }
+
+    // Keeps every logged line, installed as the logger of the whole test binary by the first test that needs it
+    struct CapturedLog(Mutex<Vec<String>>);
+
+    impl log::Log for CapturedLog {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURED_LOG: CapturedLog = CapturedLog(Mutex::new(Vec::new()));
+
+    fn captured_log(containing: &str) -> Vec<String> {
+        CAPTURED_LOG.0.lock().unwrap().iter().filter(|line| line.contains(containing)).cloned().collect()
+    }
+
+    fn capture_log() {
+        let _ = log::set_logger(&CAPTURED_LOG);
+        log::set_max_level(log::LevelFilter::Debug);
+    }
+
+    #[test]
+    fn body_logging_shows_the_start_of_the_body_and_the_handler_gets_all_of_it() {
+        capture_log();
+        async fn ugh_handler(mut req: AsyncRequest) -> Result<Response, Error> {
+            let body = req.body_bytes().await?;
+            Ok(Response::create(200, body.escape_ascii().to_string()))
+        }
+        let limits = Limits {
+            logged_body_bytes: 8,
+            ..Limits::default()
+        };
+
+        let conn = FakeConn::from_bytes(b"POST /logged HTTP/1.1\r\nHost: localhost\r\nContent-Length: 12\r\n\r\nsecret\x00\xfftail");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/logged", ugh_handler), limits);
+
+        assert!(conn.written().ends_with("\r\n\r\nsecret\\x00\\xfftail"));
+        assert_eq!(captured_log("/logged body, "), vec!["POST /logged body, 12 bytes: secret\\x00\\xff..."]);
+    }
+
+    #[test]
+    fn caught_panic_is_logged_with_its_backtrace_but_not_sent() {
+        capture_log();
+        catch_unwind::install_backtrace_hook();
+        async fn exploding_handler(_: AsyncRequest) -> &'static str {
+            panic!("kaboom")
+        }
+
+        let conn = FakeConn::new("GET /explode HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/explode", exploding_handler), Limits::default());
+
+        assert!(conn.written().starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!conn.written().contains("backtrace"));
+        let logged = captured_log("GET /explode panicked: kaboom");
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].contains("\nstack backtrace:\n"));
+        assert!(logged[0].contains("exploding_handler"));
+    }
+
+    #[test]
+    fn handler_can_return_a_plain_str() {
+        async fn ugh_handler(_: AsyncRequest) -> &'static str {
+            "hello"
+        }
+
+        let conn = FakeConn::new("GET /hello HTTP/1.1\r\nHost: host:port\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/hello", ugh_handler), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+    }
+
+    fn request_with_body(headers: &[&str], body: &[u8]) -> AsyncRequest {
+        AsyncRequest::create(
+            "POST",
+            "/upload",
+            "HTTP/1.1",
+            Arc::new(AsyncHandler::not_found("POST", "/upload")),
+            HashMap::new(),
+            Arc::new(DepsMap::default()),
+            Headers::try_from_lines(headers.iter().copied()).unwrap(),
+            Arc::new(Mutex::new(FakeConn::from_bytes(body))),
+        )
+    }
+
+    #[test]
+    fn body_bytes_returns_non_utf8_body_intact_while_body_rejects_it() {
+        let workers = Workers::new(1);
+        let body = vec![0xde, 0xad, 0xbe, 0xef, 0xff, 0x00];
+        let mut bytes_req = request_with_body(&["Content-Length: 6"], &body);
+        let mut string_req = request_with_body(&["Content-Length: 6"], &body);
+
+        let result = workers.queue_with_result(async move { (bytes_req.body_bytes().await, string_req.body().await) });
+        let (bytes, string) = result.unwrap().get();
+
+        assert_eq!(bytes.unwrap(), body);
+        assert_eq!(string.unwrap_err().status_code, 400);
+
+        workers.poison_all()
+    }
+
+    #[test]
+    fn body_bytes_reads_chunked_body() {
+        let workers = Workers::new(1);
+        let mut req = request_with_body(&["Transfer-Encoding: chunked"], b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n");
+
+        let result = workers.queue_with_result(async move { req.body_bytes().await });
+
+        assert_eq!(result.unwrap().get().unwrap(), b"Wikipedia".to_vec());
+
+        workers.poison_all()
+    }
+
+    #[test]
+    fn handler_can_send_early_hints_before_its_response() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, Error> {
+            let mut hints = Headers::new();
+            hints.insert("Link", "</style.css>; rel=preload; as=style");
+            x.send_informational(103, hints)?;
+            Ok(Response::create(200, "page".to_string()))
+        }
+
+        let conn = FakeConn::new("GET /page HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/page", ugh_handler), Limits::default());
+
+        assert_eq!(
+            conn.written(),
+            "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload; as=style\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\npage"
+        );
+    }
+
+    #[test]
+    fn informational_responses_are_refused_for_http_1_0() {
+        async fn ugh_handler(x: AsyncRequest) -> Result<Response, Error> {
+            x.send_informational(103, Headers::new())?;
+            Ok(Response::create(200, "page".to_string()))
+        }
+
+        let conn = FakeConn::new("GET /page HTTP/1.0\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("GET", "/page", ugh_handler), Limits::default());
+
+        assert!(conn.written().starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    }
+
+    #[test]
+    fn body_cut_short_by_the_client_is_a_bad_request() {
+        async fn ugh_handler(mut x: AsyncRequest) -> Result<Response, Error> {
+            Ok(Response::create(200, x.body().await?))
+        }
+
+        let conn = FakeConn::new("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nhello");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/upload", ugh_handler), Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 22\r\n\r\nUnexpected end of body");
+    }
+
+    fn json_handler() -> AsyncHandler {
+        async fn ugh_handler(mut x: AsyncRequest) -> Result<Response, Error> {
+            let name = x.json().await?["name"].as_str().unwrap_or_default().to_string();
+            Ok(Response::create(200, name))
+        }
+
+        AsyncHandler::new("POST", "/users", ugh_handler).with_json_body_limit(JsonBodyLimit { max_bytes: 32, require_object: true })
+    }
+
+    #[test]
+    fn json_body_over_the_limit_is_rejected_with_413() {
+        let body = format!("{{\"name\": \"{name}\"}}", name = "a".repeat(32));
+        let conn = FakeConn::new(&format!("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: {length}\r\n\r\n{body}", length = body.len()));
+        let (conn, _conn_state) = read_and_write(conn, json_handler(), Limits::default());
+        assert!(conn.written().starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+
+        // a chunked body has no length up front, it gets cut off once it grows past the limit
+        let conn = FakeConn::new(&format!(
+            "POST /users HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{size:x}\r\n{body}\r\n0\r\n\r\n",
+            size = body.len()
+        ));
+        let (conn, _conn_state) = read_and_write(conn, json_handler(), Limits::default());
+        assert!(conn.written().starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[test]
+    fn json_body_that_does_not_parse_or_is_not_an_object_is_rejected_with_400() {
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 8\r\n\r\n{\"name\":");
+        let (conn, _conn_state) = read_and_write(conn, json_handler(), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 22\r\n\r\nBody is not valid JSON");
+
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 7\r\n\r\n[1,2,3]");
+        let (conn, _conn_state) = read_and_write(conn, json_handler(), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 27\r\n\r\nJSON body must be an object");
+    }
+
+    #[test]
+    fn valid_json_object_reaches_the_handler_already_parsed() {
+        // the check has read the whole body, json() could not read it a second time
+        let conn = FakeConn::new("POST /users HTTP/1.1\r\nHost: localhost\r\nContent-Length: 15\r\n\r\n{\"name\": \"ugh\"}");
+        let (conn, _conn_state) = read_and_write(conn, json_handler(), Limits::default());
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 3\r\n\r\nugh");
+    }
+
+    #[test]
+    fn handler_sees_chunked_trailers_in_headers() {
+        async fn ugh_handler(mut x: AsyncRequest) -> Result<Response, Error> {
+            let body = x.body().await?;
+            let checksum = x.headers.get("x-checksum").unwrap_or("missing");
+            Ok(Response::create(200, format!("{body} {checksum}")))
+        }
+
+        let conn = FakeConn::new("POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n0\r\nX-Checksum: abc\r\nContent-Length: 1\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/upload", ugh_handler), Limits::default());
+
+        assert_eq!(conn.written(), "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 8\r\n\r\nWiki abc");
+    }
+
+    #[test]
+    fn oversized_chunk_is_rejected_before_allocating_it() {
+        async fn ugh_handler(mut x: AsyncRequest) -> Result<Response, Error> {
+            Ok(Response::create(200, x.body().await?))
+        }
+
+        let conn = FakeConn::new("POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nFFFFFFFF\r\nWiki\r\n0\r\n\r\n");
+        let (conn, _conn_state) = read_and_write(conn, AsyncHandler::new("POST", "/upload", ugh_handler), Limits::default());
+
+        assert!(conn.written().starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[test]
+    fn oversized_chunk_size_line_is_a_bad_request() {
+        let workers = Workers::new(1);
+        // the size line is body framing, not a header field
+        let mut req = request_with_body(&["Transfer-Encoding: chunked"], format!("1;ext={ext}\r\nx\r\n0\r\n\r\n", ext = "e".repeat(16 * 1024)).as_bytes());
+
+        let result = workers.queue_with_result(async move { req.body_bytes().await });
+
+        let err = result.unwrap().get().unwrap_err();
+        assert_eq!(err.status_code, 400);
+        assert_eq!(err.parse_error, Some(ParseError::ChunkLineTooLong));
+
+        workers.poison_all()
+    }
+
+    #[test]
+    fn too_many_trailers_are_rejected() {
+        let workers = Workers::new(1);
+        let trailers = (0..33).map(|i| format!("X-T{i}: v\r\n")).collect::<String>();
+        let mut req = request_with_body(&["Transfer-Encoding: chunked"], format!("0\r\n{trailers}\r\n").as_bytes());
+
+        let result = workers.queue_with_result(async move { req.body_bytes().await });
+
+        let err = result.unwrap().get().unwrap_err();
+        assert_eq!(err.status_code, 431);
+        assert_eq!(err.parse_error, Some(ParseError::TooManyTrailers));
+
+        workers.poison_all()
+    }
+
+    #[test]
+    fn malformed_chunk_size_is_reported_as_such() {
+        let workers = Workers::new(1);
+        let mut req = request_with_body(&["Transfer-Encoding: chunked"], b"zz\r\nWiki\r\n0\r\n\r\n");
+
+        let result = workers.queue_with_result(async move { req.body_bytes().await });
+
+        let err = result.unwrap().get().unwrap_err();
+        assert_eq!((err.status_code, err.parse_error), (400, Some(ParseError::BadChunkSize)));
+
+        workers.poison_all()
+    }
+
+    #[test]
+    fn body_stream_yields_a_chunked_upload_chunk_by_chunk() {
+        let workers = Workers::new(1);
+        let mut req = request_with_body(&["Transfer-Encoding: chunked"], b"4\r\nWiki\r\n5\r\npedia\r\n3\r\n in\r\n0\r\nX-Checksum: abc\r\n\r\n");
+
+        let result = workers.queue_with_result(async move {
+            let mut sizes = Vec::new();
+            let mut body = req.body_stream().unwrap();
+            while let Some(piece) = body.next().await {
+                sizes.push(piece.unwrap().len());
+            }
+            (sizes, req.headers.get("x-checksum").map(str::to_string))
+        });
+        let (sizes, checksum) = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(sizes, vec![4, 5, 3]);
+        assert_eq!(sizes.iter().sum::<usize>(), 12);
+        assert_eq!(checksum.as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn body_stream_splits_a_large_content_length_body() {
+        let workers = Workers::new(1);
+        let body = vec![b'x'; 40 * 1024];
+        let mut req = request_with_body(&[&format!("Content-Length: {len}", len = body.len())], &body);
+
+        let result = workers.queue_with_result(async move {
+            let mut sizes = Vec::new();
+            let mut body = req.body_stream().unwrap();
+            while let Some(piece) = body.next().await {
+                sizes.push(piece.unwrap().len());
+            }
+            sizes
+        });
+        let sizes = result.unwrap().get();
+        workers.poison_all();
+
+        assert!(sizes.len() > 1);
+        assert_eq!(sizes.iter().sum::<usize>(), body.len());
+    }
+
+    #[test]
+    fn body_to_file_streams_the_body_to_disk() {
+        let workers = Workers::new(1);
+        let body = (0..1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut req = request_with_body(&[&format!("Content-Length: {len}", len = body.len())], &body);
+        let path = std::env::temp_dir().join(format!("nvo_servers_upload_{pid}_{n}", pid = std::process::id(), n = utils::poor_mans_random()));
+
+        let result = workers.queue_with_result({
+            let path = path.clone();
+            async move { req.body_to_file(&path).await }
+        });
+        let written = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(written, Ok(body.len() as u64));
+        assert_eq!(std::fs::read(&path).unwrap(), body);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn body_to_file_enforces_the_body_size_limit() {
+        let workers = Workers::new(1);
+        let mut req = request_with_body(&["Transfer-Encoding: chunked"], b"4\r\nWiki\r\n4\r\nWiki\r\n0\r\n\r\n");
+        req.limits.max_body_size = 6;
+        let path = std::env::temp_dir().join(format!("nvo_servers_upload_{pid}_{n}", pid = std::process::id(), n = utils::poor_mans_random()));
+
+        let result = workers.queue_with_result({
+            let path = path.clone();
+            async move { req.body_to_file(&path).await }
+        });
+        let written = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(written.unwrap_err().status_code, 413);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn body_stream_stops_after_an_error() {
+        let workers = Workers::new(1);
+        let mut req = request_with_body(&["Content-Length: 10"], b"hello");
+
+        let result = workers.queue_with_result(async move {
+            let mut body = req.body_stream().unwrap();
+            (
+                body.next().await.map(|piece| piece.map(|piece| piece.len())),
+                body.next().await.map(|piece| piece.map_err(|e| e.status_code)),
+                body.next().await.is_none(),
+            )
+        });
+        let (first, second, done) = result.unwrap().get();
+        workers.poison_all();
+
+        assert_eq!(first, Some(Ok(5)));
+        assert_eq!(second, Some(Err(400)));
+        assert!(done);
     }
 
     // #[test]
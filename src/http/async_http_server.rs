@@ -1,65 +1,360 @@
 use std::{
     any::Any,
     collections::{HashMap, HashSet},
-    net::TcpStream,
-    sync::{atomic::AtomicBool, Arc, Mutex},
+    fmt, io, mem,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-use crate::{futures::workers::Workers, typemap::DepsMap};
+use log::{debug, warn};
+use serde_json::json;
+use socket2::{Domain, Socket, Type};
 
-use super::{async_handler::AsyncHandler, ConnState};
+use crate::{
+    futures::{
+        catch_unwind,
+        sync::Latch,
+        workers::{Workers, WorkersConfig},
+    },
+    typemap::DepsMap,
+};
+
+use super::{
+    async_handler::{AsyncHandler, AsyncHandlerFn, EXPLICIT_ONLY_METHODS},
+    headers::Headers,
+    limits::Limits,
+    middleware::Middleware,
+    response::Response,
+    server_config::ServerConfig,
+    AsyncRequest, ConnState,
+};
+
+// How long an acceptor blocks waiting for events before it re-checks whether a shutdown has been requested
+pub(crate) const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+// How often an acceptor looks for idle connections, see with_idle_timeout
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+// Keyed by connection id, see conn_id, the instant is when the connection last made progress, see with_idle_timeout
+pub type Connections = HashMap<u64, (TcpStream, ConnState, Instant)>;
 
 pub trait AsyncHttpServerTrt {
     fn builder() -> AsyncHttpServerBuilder;
-    fn start_blocking(&self);
+    // Fails right away when the server cannot listen, e.g. the port is taken. Otherwise serves until stopped.
+    fn try_start_blocking(&self) -> io::Result<()>;
+    fn start_blocking(&self) {
+        self.try_start_blocking().unwrap_or_else(|e| crate::log_panic!("{reason}", reason = e.to_string()))
+    }
     fn shutdown_gracefully(self);
+    fn shutdown_gracefully_timeout(self, timeout: Duration);
 }
 
 pub struct AsyncHttpServer {
     pub listen_addr: String,
-    pub endpoints: HashSet<Arc<AsyncHandler>>,
+    // served next to listen_addr, by the same routes and workers
+    pub additional_addrs: Vec<String>,
+    // Behind a lock so routes can be added while serving, see add_route
+    pub endpoints: RwLock<HashSet<Arc<AsyncHandler>>>,
+    pub fallback: Option<Arc<AsyncHandler>>,
     pub workers: Workers,
-    pub connections: Arc<Mutex<HashMap<i32, (TcpStream, ConnState)>>>,
-    pub started: AtomicBool,
+    pub acceptors: usize,
+    pub connections: Arc<Mutex<Connections>>,
+    // bumped for every accepted connection, see conn_id
+    conn_generation: AtomicU64,
+    // Shared with the readiness endpoint, see with_readiness_endpoint
+    pub started: Arc<AtomicBool>,
+    // opened together with `started`, or when binding failed, see ready
+    ready: Latch,
+    // why binding failed, kept for ready as io::Error is not Clone
+    start_error: OnceLock<(io::ErrorKind, String)>,
     pub shutdown_requested: AtomicBool,
-    pub deps_map: Arc<DepsMap>,
+    handles_signals: AtomicBool,
+    // Swapped as a whole by update_deps, a request keeps the map it started with
+    pub deps_map: RwLock<Arc<DepsMap>>,
+    pub limits: Limits,
+    pub default_headers: Arc<Headers>,
+    pub propagate_panics: bool,
+    pub close_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub trace_and_connect_disabled: bool,
+    // applied to every route, including the ones added later with add_route
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    // shared with the ServerConfig in the deps
+    local_addrs: Arc<OnceLock<Vec<SocketAddr>>>,
 }
 
 pub struct AsyncHttpServerBuilder {
     pub listen_addr: String,
+    pub additional_addrs: Vec<String>,
     pub handlers: HashSet<AsyncHandler>,
+    pub fallback: Option<AsyncHandler>,
     pub workers_number: usize,
+    pub workers_config: WorkersConfig,
+    pub acceptors_number: usize,
     pub deps_map: DepsMap,
+    pub limits: Limits,
+    pub default_headers: Headers,
+    pub propagate_panics: bool,
+    pub close_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub trace_and_connect_disabled: bool,
+    pub panic_backtraces: bool,
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    pub scheme: String,
+    pub base_url: Option<String>,
+    pub method_override: bool,
+    started: Arc<AtomicBool>,
+}
+
+impl AsyncHttpServer {
+    // Every address is bound once, without SO_REUSEPORT, so an address some other process (or server) listens on is an AddrInUse error
+    // instead of the kernel quietly splitting connections between the two. Every acceptor gets its own handle on the same listener.
+    // A failure also resolves ready, with the same error
+    pub(crate) fn bind_listeners(&self) -> io::Result<Vec<Vec<TcpListener>>> {
+        self.bind_all().inspect_err(|e| {
+            let _ = self.start_error.set((e.kind(), e.to_string()));
+            self.ready.open();
+        })
+    }
+
+    fn bind_all(&self) -> io::Result<Vec<Vec<TcpListener>>> {
+        let firsts = self
+            .listen_addrs()
+            .map(|listen_addr| Self::bind(listen_addr).map_err(|e| io::Error::new(e.kind(), format!("Could not start listening on {listen_addr}, reason:\n{e}"))))
+            .collect::<io::Result<Vec<TcpListener>>>()?;
+        let bound_addrs = firsts.iter().map(TcpListener::local_addr).collect::<io::Result<Vec<SocketAddr>>>()?;
+
+        let mut listeners = Vec::new();
+        for _ in 1..self.acceptors.max(1) {
+            listeners.push(firsts.iter().map(TcpListener::try_clone).collect::<io::Result<Vec<TcpListener>>>()?);
+        }
+        listeners.insert(0, firsts);
+        let _ = self.local_addrs.set(bound_addrs);
+        Ok(listeners)
+    }
+
+    fn listen_addrs(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.listen_addr.as_str()).chain(self.additional_addrs.iter().map(String::as_str))
+    }
+
+    // SO_REUSEADDR only lets a restarted server bind while connections of the old one linger in TIME_WAIT, not share a listening address
+    fn bind(listen_addr: &str) -> io::Result<TcpListener> {
+        let addr = listen_addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Could not resolve {listen_addr}")))?;
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket.into())
+    }
+
+    // Safe to call while the server is running, requests dispatched afterwards see the new route. A route with the same method and path
+    // gets replaced. The cost is a read lock taken for every dispatched event, which an add_route briefly blocks.
+    // Fails, leaving the routes as they were, when the handler requires a dep the server does not have.
+    pub fn try_add_route(&self, handler: AsyncHandler) -> Result<(), MissingDep> {
+        AsyncHttpServerBuilder::check_method(&handler);
+        AsyncHttpServerBuilder::check_required_deps([&handler], &self.deps_snapshot())?;
+        if AsyncHttpServerBuilder::is_enabled(&handler, self.trace_and_connect_disabled) {
+            self.endpoints.write().expect("poisoned lock").replace(Arc::new(handler.behind(&self.middlewares)));
+        }
+        Ok(())
+    }
+
+    pub fn add_route(&self, handler: AsyncHandler) {
+        self.try_add_route(handler).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    // After this SIGINT/SIGTERM no longer kill the process, they stop the accept loops instead (start_blocking returns).
+    // In flight requests keep running on the workers, shutdown_gracefully still has to be called to wait for them.
+    #[cfg(unix)]
+    pub fn install_signal_handler(&self) -> io::Result<()> {
+        super::shutdown_signal::install()?;
+        self.handles_signals.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Resolves once an accept loop is running, from then on connections get served. When the server fails to start it resolves
+    // with the error try_start_blocking returns.
+    pub async fn ready(&self) -> io::Result<()> {
+        self.ready.wait().await;
+        match self.start_error.get() {
+            Some((kind, reason)) => Err(io::Error::new(*kind, reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // Called by the accept loops on every round
+    pub(crate) fn mark_started(&self) {
+        if !self.ready.is_open() {
+            self.started.store(true, Ordering::SeqCst);
+            self.ready.open();
+        }
+    }
+
+    pub(crate) fn should_stop(&self) -> bool {
+        #[cfg(unix)]
+        if self.handles_signals.load(Ordering::SeqCst) && super::shutdown_signal::received() {
+            return true;
+        }
+        self.shutdown_requested.load(Ordering::SeqCst)
+    }
+
+    // Half closes a connection the handler is done with, see with_graceful_close. None means it can be dropped right away.
+    pub(crate) fn closing_state(conn: &TcpStream, timeout: Duration) -> Option<ConnState> {
+        conn.shutdown(Shutdown::Write).ok()?;
+        Some(ConnState::Closing(Instant::now() + timeout))
+    }
+
+    // Keeps the old instant when the connection got nowhere, e.g. a readable event that brought no bytes
+    pub(crate) fn last_active(before: (mem::Discriminant<ConnState>, usize), after: &ConnState, last_active: Instant) -> Instant {
+        if after.progress() == before {
+            last_active
+        } else {
+            Instant::now()
+        }
+    }
+
+    // The fd in the low bits, a generation never reused in the high ones. A closed connection's fd gets handed out again, an event still
+    // queued for the old one (in any acceptor) must not reach the new one. Listeners are registered with their bare fd, generation 0.
+    pub(crate) fn conn_id(&self, fd: i32) -> u64 {
+        (self.conn_generation.fetch_add(1, Ordering::Relaxed) << 32) | fd as u32 as u64
+    }
+
+    // Drops every connection that has not made progress for longer than the idle timeout. Closing the socket also takes it out of
+    // the epoll/kqueue it is registered with. Connections a worker is busy with are not in the map, so they never get swept.
+    pub(crate) fn sweep_idle_connections(&self) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        self.connections.lock().expect("Poisoned").retain(|&id, (_, state, last_active)| {
+            let idle = last_active.elapsed() > idle_timeout;
+            if idle {
+                debug!("Dropping connection {fd}, idle in state {state} for {idle_for:?}", fd = id as i32, idle_for = last_active.elapsed());
+            }
+            !idle
+        });
+    }
+
+    // Replaces every dep at once, e.g. to reload config. Requests already being handled keep seeing the old map, later ones only the new one.
+    // The ServerConfig put in by build carries over unless `deps_map` has one of its own.
+    // Fails when a route's required dep is missing from `deps_map`, the old map stays in place then.
+    pub fn try_update_deps(&self, mut deps_map: DepsMap) -> Result<(), MissingDep> {
+        if let Some(config) = self.deps_snapshot().get::<ServerConfig>().filter(|_| !deps_map.contains::<ServerConfig>()) {
+            deps_map.insert(config.clone());
+        }
+        let endpoints = self.endpoints_snapshot();
+        AsyncHttpServerBuilder::check_required_deps(endpoints.iter().map(Arc::as_ref).chain(self.fallback.as_deref()), &deps_map)?;
+        *self.deps_map.write().expect("poisoned lock") = Arc::new(deps_map);
+        Ok(())
+    }
+
+    pub fn update_deps(&self, deps_map: DepsMap) {
+        self.try_update_deps(deps_map).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    pub(crate) fn deps_snapshot(&self) -> Arc<DepsMap> {
+        self.deps_map.read().expect("poisoned lock").clone()
+    }
+
+    pub(crate) fn endpoints_snapshot(&self) -> HashSet<Arc<AsyncHandler>> {
+        self.endpoints.read().expect("poisoned lock").clone()
+    }
+
+    // The address the server actually listens on, known once it has started. Handy with port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs().first().copied()
+    }
+
+    // Same as local_addr, for listen_addr followed by the additional addresses in the order they were added
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        self.local_addrs.get().map_or(&[], Vec::as_slice)
+    }
 }
 
 impl AsyncHttpServerBuilder {
+    // `host:port`, or just a host which keeps the port already set. An out of range port is reported by start, like any other address
+    // that cannot be listened on.
     pub fn with_addr(mut self, addr: &str) -> AsyncHttpServerBuilder {
         if addr.contains(':') {
             self.listen_addr = addr.to_string();
         } else {
-            let mut split = addr.split(':');
-            self.listen_addr = format!("{addr}:{port}", port = split.nth(1).unwrap());
+            let port = self.listen_addr.rsplit_once(':').map_or("", |(_, port)| port);
+            self.listen_addr = format!("{addr}:{port}");
         }
         self
     }
 
-    pub fn with_port(mut self, port: usize) -> AsyncHttpServerBuilder {
-        if port > 65536 {
-            panic!("Port cannot be larger than 65535. Was: {port}")
-        }
-        let hostname = self.listen_addr.split(':').nth(0).unwrap();
+    // u16, so a port out of range does not compile in the first place
+    pub fn with_port(mut self, port: u16) -> AsyncHttpServerBuilder {
+        let hostname = self.listen_addr.rsplit_once(':').map_or(self.listen_addr.as_str(), |(hostname, _)| hostname);
         self.listen_addr = format!("{hostname}:{port}");
         self
     }
 
+    // Listens on `addr` as well, e.g. `0.0.0.0:8080` next to the main address. Can be called repeatedly.
+    pub fn with_additional_addr(mut self, addr: &str) -> AsyncHttpServerBuilder {
+        self.additional_addrs.push(addr.to_string());
+        self
+    }
+
     pub fn with_handlers(mut self, handlers: HashSet<AsyncHandler>) -> AsyncHttpServerBuilder {
         handlers.into_iter().for_each(|ele| {
+            Self::check_method(&ele);
             self.handlers.insert(ele);
         });
         self
     }
 
+    // A single route, e.g. `.route("PATCH", "/users/:id", patch_user)`. get, post, put and delete cover the common methods.
+    pub fn route(self, method: &str, path: &str, handler: impl AsyncHandlerFn) -> AsyncHttpServerBuilder {
+        self.with_handlers(HashSet::from([AsyncHandler::new(method, path, handler)]))
+    }
+
+    pub fn get(self, path: &str, handler: impl AsyncHandlerFn) -> AsyncHttpServerBuilder {
+        self.route("GET", path, handler)
+    }
+
+    pub fn post(self, path: &str, handler: impl AsyncHandlerFn) -> AsyncHttpServerBuilder {
+        self.route("POST", path, handler)
+    }
+
+    pub fn put(self, path: &str, handler: impl AsyncHandlerFn) -> AsyncHttpServerBuilder {
+        self.route("PUT", path, handler)
+    }
+
+    pub fn delete(self, path: &str, handler: impl AsyncHandlerFn) -> AsyncHttpServerBuilder {
+        self.route("DELETE", path, handler)
+    }
+
+    // Registers the handlers under a common prefix, e.g. `/api/v1` + `/status` -> `/api/v1/status`. The prefix can have parameters of its own.
+    pub fn with_scope(self, prefix: &str, handlers: HashSet<AsyncHandler>) -> AsyncHttpServerBuilder {
+        self.with_handlers(handlers.into_iter().map(|handler| handler.prefixed(prefix)).collect())
+    }
+
+    // Virtual host: the handlers only serve requests whose Host header names `host` (port and case do not matter)
+    pub fn with_vhost(self, host: &str, handlers: HashSet<AsyncHandler>) -> AsyncHttpServerBuilder {
+        self.with_handlers(handlers.into_iter().map(|handler| handler.for_host(host)).collect())
+    }
+
+    // Runs around every route (and the fallback) in the order added, before the route's own middlewares
+    pub fn with_middleware(mut self, middleware: impl Middleware) -> AsyncHttpServerBuilder {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    // Handles every request no registered handler matched, instead of the built-in 404
+    pub fn with_fallback(mut self, fallback: AsyncHandler) -> AsyncHttpServerBuilder {
+        self.fallback = Some(fallback);
+        self
+    }
+
     pub fn with_dep(mut self, dep: impl Any + Sync + Send) -> AsyncHttpServerBuilder {
         self.deps_map.insert(dep);
         self
@@ -75,27 +370,428 @@ impl AsyncHttpServerBuilder {
         self
     }
 
+    // Threads accepting connections, all of them on the same listeners. With epoll a new connection wakes one of them.
+    pub fn with_acceptors(mut self, num_acceptors: usize) -> AsyncHttpServerBuilder {
+        if num_acceptors == 0 {
+            panic!("At least one acceptor is required.")
+        }
+        self.acceptors_number = num_acceptors;
+        self
+    }
+
+    // Worker threads get named `{prefix}{n}`
+    pub fn with_worker_name_prefix(mut self, prefix: &str) -> AsyncHttpServerBuilder {
+        self.workers_config.name_prefix = prefix.to_string();
+        self
+    }
+
+    pub fn with_worker_stack_size(mut self, stack_size: usize) -> AsyncHttpServerBuilder {
+        self.workers_config.stack_size = Some(stack_size);
+        self
+    }
+
+    pub fn with_max_header_count(mut self, max_header_count: usize) -> AsyncHttpServerBuilder {
+        self.limits.max_header_count = max_header_count;
+        self
+    }
+
+    pub fn with_max_header_line_length(mut self, max_header_line_length: usize) -> AsyncHttpServerBuilder {
+        self.limits.max_header_line_length = max_header_line_length;
+        self
+    }
+
+    // Request line and headers together. Raising with_max_uri_length or with_max_header_line_length makes room for that one line anyway.
+    pub fn with_max_head_size(mut self, max_head_size: usize) -> AsyncHttpServerBuilder {
+        self.limits.max_head_size = max_head_size;
+        self
+    }
+
+    pub fn with_max_chunk_size(mut self, max_chunk_size: usize) -> AsyncHttpServerBuilder {
+        self.limits.max_chunk_size = max_chunk_size;
+        self
+    }
+
+    pub fn with_max_uri_length(mut self, max_uri_length: usize) -> AsyncHttpServerBuilder {
+        self.limits.max_uri_length = max_uri_length;
+        self
+    }
+
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> AsyncHttpServerBuilder {
+        self.limits.max_body_size = max_body_size;
+        self
+    }
+
+    // Debugging aid, the first `max_bytes` of every body a handler reads go to the debug log. The handler still gets the whole body.
+    pub fn with_body_logging(mut self, max_bytes: usize) -> AsyncHttpServerBuilder {
+        self.limits.logged_body_bytes = max_bytes;
+        self
+    }
+
+    // Added to every response that does not set the same header itself, e.g. security headers like X-Content-Type-Options
+    pub fn with_default_headers(mut self, default_headers: Headers) -> AsyncHttpServerBuilder {
+        self.default_headers = default_headers;
+        self
+    }
+
+    // Off by default, handler panics become 500s. Turned on they unwind out of the request handling into the worker,
+    // which logs them and drops the connection. Handy with a debugger set to break on unwinding.
+    pub fn with_panic_propagation(mut self, propagate_panics: bool) -> AsyncHttpServerBuilder {
+        self.propagate_panics = propagate_panics;
+        self
+    }
+
+    // Caught handler panics get logged with a backtrace, the client still only gets a 500. Installs a process wide panic hook,
+    // every panic pays for a stack walk from then on.
+    pub fn with_panic_backtraces(mut self, panic_backtraces: bool) -> AsyncHttpServerBuilder {
+        self.panic_backtraces = panic_backtraces;
+        self
+    }
+
+    // Closing a connection the client is still sending on (e.g. an upload the handler did not read) resets it, which can throw away
+    // the response before the client got to read it. With this the server half closes and drains instead, for at most `timeout`.
+    pub fn with_graceful_close(mut self, timeout: Duration) -> AsyncHttpServerBuilder {
+        self.close_timeout = Some(timeout);
+        self
+    }
+
+    // Connections that make no progress for longer than `timeout` get dropped, e.g. kept alive ones the client no longer uses, clients that
+    // went away without closing or never finish sending a request. Checked by the acceptors roughly every SWEEP_INTERVAL.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> AsyncHttpServerBuilder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    // TRACE and CONNECT get a 405 even when a route has been registered for them, the route is dropped with a warning
+    pub fn with_trace_and_connect_disabled(mut self) -> AsyncHttpServerBuilder {
+        self.trace_and_connect_disabled = true;
+        self
+    }
+
+    // Only goes into the ServerConfig handlers see, the server itself always speaks plain http
+    pub fn with_scheme(mut self, scheme: &str) -> AsyncHttpServerBuilder {
+        self.scheme = scheme.to_string();
+        self
+    }
+
+    // What handlers should build absolute links from, e.g. the public address of a proxy in front of the server. See ServerConfig::base_url.
+    pub fn with_base_url(mut self, base_url: &str) -> AsyncHttpServerBuilder {
+        self.base_url = Some(base_url.to_string());
+        self
+    }
+
+    // A POST carrying X-HTTP-Method-Override gets routed as the method it names, for clients stuck with GET and POST (e.g. HTML forms).
+    // Off by default, it lets anything that can send a POST reach the PUT and DELETE routes.
+    pub fn with_method_override(mut self, enabled: bool) -> AsyncHttpServerBuilder {
+        self.method_override = enabled;
+        self
+    }
+
+    // Liveness probe, a GET on `path` answers 200 as long as requests get served at all
+    pub fn with_health_endpoint(self, path: &str) -> AsyncHttpServerBuilder {
+        async fn health_handler(_: AsyncRequest) -> Result<Response, String> {
+            Ok(AsyncHttpServerBuilder::status_response(200, "ok"))
+        }
+
+        self.with_handlers(HashSet::from([AsyncHandler::new("GET", path, health_handler)]))
+    }
+
+    // Readiness probe, a GET on `path` answers 503 until the server has started accepting connections, 200 afterwards
+    pub fn with_readiness_endpoint(self, path: &str) -> AsyncHttpServerBuilder {
+        let started = self.started.clone();
+        self.with_handlers(HashSet::from([AsyncHandler::from_fn_with_state("GET", path, started, |started, _| async move {
+            if started.load(Ordering::SeqCst) {
+                Ok::<Response, String>(Self::status_response(200, "ok"))
+            } else {
+                Ok(Self::status_response(503, "starting"))
+            }
+        })]))
+    }
+
+    fn status_response(status_code: u16, status: &str) -> Response {
+        Response::create(status_code, json!({ "status": status }).to_string()).with_content_type("application/json")
+    }
+
+    fn check_method(handler: &AsyncHandler) {
+        if handler.method.is_empty() {
+            panic!("Handler for path: '{path}' has an empty method, use ANY_METHOD to match every method.", path = handler.path)
+        }
+    }
+
+    fn check_required_deps<'a>(handlers: impl IntoIterator<Item = &'a AsyncHandler>, deps_map: &DepsMap) -> Result<(), MissingDep> {
+        for handler in handlers {
+            if let Some((_, dep)) = handler.required_deps.iter().find(|(type_id, _)| !deps_map.contains_type_id(*type_id)) {
+                return Err(MissingDep {
+                    method: handler.method.clone(),
+                    path: handler.path.clone(),
+                    dep,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn is_enabled(handler: &AsyncHandler, trace_and_connect_disabled: bool) -> bool {
+        if trace_and_connect_disabled && EXPLICIT_ONLY_METHODS.contains(&handler.method.as_str()) {
+            warn!("Dropping route {method} '{path}', TRACE and CONNECT are disabled", method = handler.method, path = handler.path);
+            return false;
+        }
+        true
+    }
+
     pub fn build(self) -> AsyncHttpServer {
-        AsyncHttpServer {
+        self.try_build().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    // Fails when a handler requires a dep that has not been added with with_dep.
+    pub fn try_build(mut self) -> Result<AsyncHttpServer, MissingDep> {
+        if self.panic_backtraces {
+            catch_unwind::install_backtrace_hook();
+        }
+        let local_addrs = Arc::new(OnceLock::new());
+        // one added with with_dep wins
+        if !self.deps_map.contains::<ServerConfig>() {
+            self.deps_map.insert(ServerConfig {
+                listen_addr: self.listen_addr.clone(),
+                scheme: self.scheme.clone(),
+                base_url: self.base_url.clone(),
+                limits: self.limits,
+                method_override: self.method_override,
+                bound_addrs: local_addrs.clone(),
+            });
+        }
+        Self::check_required_deps(self.handlers.iter().chain(&self.fallback), &self.deps_map)?;
+        let disabled = self.trace_and_connect_disabled;
+        Ok(AsyncHttpServer {
             listen_addr: self.listen_addr,
-            endpoints: self.handlers.into_iter().map(Arc::new).collect(),
-            workers: Workers::new(self.workers_number),
+            additional_addrs: self.additional_addrs,
+            endpoints: RwLock::new(
+                self.handlers
+                    .into_iter()
+                    .filter(|handler| Self::is_enabled(handler, disabled))
+                    .map(|handler| Arc::new(handler.behind(&self.middlewares)))
+                    .collect(),
+            ),
+            fallback: self.fallback.map(|fallback| Arc::new(fallback.behind(&self.middlewares))),
+            workers: Workers::with_config(self.workers_number, self.workers_config),
+            acceptors: self.acceptors_number,
             connections: Default::default(),
-            started: AtomicBool::new(false),
+            conn_generation: AtomicU64::new(1),
+            started: self.started,
+            ready: Latch::new(),
+            start_error: OnceLock::new(),
             shutdown_requested: AtomicBool::new(false),
-            deps_map: Arc::new(self.deps_map),
-        }
+            handles_signals: AtomicBool::new(false),
+            deps_map: RwLock::new(Arc::new(self.deps_map)),
+            limits: self.limits,
+            default_headers: Arc::new(self.default_headers),
+            propagate_panics: self.propagate_panics,
+            close_timeout: self.close_timeout,
+            idle_timeout: self.idle_timeout,
+            trace_and_connect_disabled: self.trace_and_connect_disabled,
+            middlewares: self.middlewares,
+            local_addrs,
+        })
+    }
+}
+
+// A handler requires a dep, see AsyncHandler::with_required_dep, that the server has not got
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MissingDep {
+    pub method: String,
+    pub path: String,
+    // the type name of the dep
+    pub dep: &'static str,
+}
+
+impl fmt::Display for MissingDep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Handler for {method} '{path}' requires the dep {dep}, which has not been added with with_dep.",
+            method = self.method,
+            path = self.path,
+            dep = self.dep
+        )
     }
 }
 
+impl std::error::Error for MissingDep {}
+
 impl Default for AsyncHttpServerBuilder {
     fn default() -> Self {
         let thread_count = thread::available_parallelism().unwrap().get();
         Self {
             listen_addr: "0.0.0.0:9000".to_string(),
+            additional_addrs: Vec::new(),
             handlers: Default::default(),
+            fallback: None,
             workers_number: thread_count,
+            workers_config: WorkersConfig::default(),
+            acceptors_number: 1,
             deps_map: DepsMap::default(),
+            limits: Limits::default(),
+            default_headers: Headers::new(),
+            propagate_panics: false,
+            close_timeout: None,
+            idle_timeout: None,
+            trace_and_connect_disabled: false,
+            panic_backtraces: false,
+            middlewares: Vec::new(),
+            scheme: "http".to_string(),
+            base_url: None,
+            method_override: false,
+            started: Arc::new(AtomicBool::new(false)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use crate::futures::workers::Workers;
+    use crate::http::{async_handler::AsyncHandler, headers::Headers, response::Response, AsyncRequest};
+    use crate::typemap::DepsMap;
+
+    use super::{AsyncHttpServerBuilder, ServerConfig};
+
+    async fn ugh_handler(_: AsyncRequest) -> Result<Response, String> {
+        Ok(Response::create(200, String::new()))
+    }
+
+    #[test]
+    #[should_panic(expected = "empty method")]
+    fn handler_with_an_empty_method_cannot_be_registered() {
+        let _ = AsyncHttpServerBuilder::default().with_handlers(HashSet::from([AsyncHandler::new("", "/users", ugh_handler)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "empty method")]
+    fn route_with_an_empty_method_cannot_be_added() {
+        let server = AsyncHttpServerBuilder::default().with_custom_num_workers(1).build();
+        server.add_route(AsyncHandler::new("", "/users", ugh_handler));
+    }
+
+    #[test]
+    #[should_panic(expected = "Handler for GET '/users' requires the dep alloc::string::String")]
+    fn handler_with_a_missing_required_dep_cannot_be_built() {
+        let _ = AsyncHttpServerBuilder::default()
+            .with_dep(42_u32)
+            .with_handlers(HashSet::from([AsyncHandler::new("GET", "/users", ugh_handler)
+                .with_required_dep::<u32>()
+                .with_required_dep::<String>()]))
+            .build();
+    }
+
+    #[test]
+    fn required_deps_are_checked_on_every_change() {
+        let server = AsyncHttpServerBuilder::default()
+            .with_custom_num_workers(1)
+            .with_dep("db".to_string())
+            .with_handlers(HashSet::from([AsyncHandler::new("GET", "/users", ugh_handler).with_required_dep::<String>()]))
+            .build();
+
+        let route_added = server.try_add_route(AsyncHandler::new("GET", "/orders", ugh_handler).with_required_dep::<u32>());
+        let deps_updated = server.try_update_deps(DepsMap::default());
+
+        assert_eq!(route_added.map_err(|e| (e.method, e.path, e.dep)), Err(("GET".to_string(), "/orders".to_string(), "u32")));
+        assert_eq!(
+            deps_updated.map_err(|e| e.to_string()),
+            Err("Handler for GET '/users' requires the dep alloc::string::String, which has not been added with with_dep.".to_string())
+        );
+        assert_eq!(server.endpoints_snapshot().len(), 1);
+        assert_eq!(server.deps_snapshot().get::<String>().map(String::as_str), Some("db"));
+    }
+
+    #[test]
+    fn reused_fd_gets_a_new_connection_id() {
+        let server = AsyncHttpServerBuilder::default().with_custom_num_workers(1).build();
+
+        let (first, second) = (server.conn_id(7), server.conn_id(7));
+
+        assert_ne!(first, second);
+        assert_eq!((first as i32, second as i32), (7, 7));
+        // never mistaken for a listener, those are registered with their bare fd
+        assert_ne!(first, 7);
+    }
+
+    #[test]
+    fn host_and_port_can_be_set_separately() {
+        assert_eq!(AsyncHttpServerBuilder::default().with_addr("127.0.0.1").listen_addr, "127.0.0.1:9000");
+        assert_eq!(AsyncHttpServerBuilder::default().with_port(8090).with_addr("127.0.0.1").listen_addr, "127.0.0.1:8090");
+        assert_eq!(AsyncHttpServerBuilder::default().with_addr("[::1]:8080").with_port(8090).listen_addr, "[::1]:8090");
+    }
+
+    #[test]
+    fn disabled_trace_drops_its_routes() {
+        let server = AsyncHttpServerBuilder::default()
+            .with_custom_num_workers(1)
+            .with_handlers(HashSet::from([AsyncHandler::new("TRACE", "/echo", ugh_handler), AsyncHandler::new("GET", "/echo", ugh_handler)]))
+            .with_trace_and_connect_disabled()
+            .build();
+        server.add_route(AsyncHandler::new("CONNECT", "/tunnel", ugh_handler));
+
+        let methods = server.endpoints_snapshot().iter().map(|handler| handler.method.clone()).collect::<Vec<_>>();
+        assert_eq!(methods, vec!["GET"]);
+    }
+
+    #[test]
+    fn updated_deps_keep_the_server_config() {
+        let server = AsyncHttpServerBuilder::default().with_custom_num_workers(1).with_dep(1u32).with_base_url("https://example.com").build();
+        let before = server.deps_snapshot();
+
+        let mut deps = DepsMap::new();
+        deps.insert(2u32);
+        server.update_deps(deps);
+
+        let after = server.deps_snapshot();
+        assert_eq!(before.get::<u32>(), Some(&1));
+        assert_eq!(after.get::<u32>(), Some(&2));
+        assert_eq!(after.get::<ServerConfig>().unwrap().base_url(), "https://example.com");
+    }
+
+    #[test]
+    fn worker_name_prefix_reaches_the_worker_threads() {
+        let server = AsyncHttpServerBuilder::default()
+            .with_custom_num_workers(1)
+            .with_worker_name_prefix("http-")
+            .with_worker_stack_size(4 * 1024 * 1024)
+            .build();
+
+        let name = server.workers.queue_with_result(async { thread::current().name().map(str::to_string) }).unwrap().get();
+
+        assert_eq!(name.as_deref(), Some("http-0"));
+    }
+
+    #[test]
+    fn readiness_endpoint_is_unavailable_until_started() {
+        let server = AsyncHttpServerBuilder::default().with_custom_num_workers(1).with_readiness_endpoint("/ready").build();
+        let handler = server.endpoints_snapshot().into_iter().next().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let req = AsyncRequest::create(
+            "GET",
+            "/ready",
+            "HTTP/1.1",
+            handler.clone(),
+            HashMap::new(),
+            Arc::new(DepsMap::default()),
+            Headers::new(),
+            Arc::new(Mutex::new(conn)),
+        );
+
+        let workers = Workers::new(1);
+        let before = workers.queue_with_result(handler.func.call(req.clone())).unwrap().get().unwrap();
+        server.started.store(true, Ordering::SeqCst);
+        let after = workers.queue_with_result(handler.func.call(req)).unwrap().get().unwrap();
+        workers.poison_all();
+
+        assert_eq!(before.status_code, 503);
+        assert_eq!(after.status_code, 200);
+        assert_eq!(after.response_body, r#"{"status":"ok"}"#);
+    }
+}
@@ -1,75 +1,139 @@
 use super::async_handler::AsyncHandler;
-use super::async_http_server::{AsyncHttpServer, AsyncHttpServerBuilder, AsyncHttpServerTrt};
+use super::async_http_server::{AsyncHttpServer, AsyncHttpServerBuilder, AsyncHttpServerTrt, POLL_TIMEOUT, SWEEP_INTERVAL};
 use super::ConnState;
 use crate::log_panic;
-use epoll::ControlOptions::EPOLL_CTL_ADD;
+use epoll::ControlOptions::{EPOLL_CTL_ADD, EPOLL_CTL_MOD};
 use epoll::{Event, Events};
-use log::error;
+use log::{debug, error};
 use std::io;
 use std::net::TcpListener;
 use std::os::fd::AsRawFd;
 use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
 
 impl AsyncHttpServerTrt for AsyncHttpServer {
-    fn start_blocking(&self) {
-        let listener = TcpListener::bind(&self.listen_addr).unwrap_or_else(|e| log_panic!("Could not start listening on {addr}, reason:\n{reason}", addr = self.listen_addr, reason = e.to_string()));
-        listener
-            .set_nonblocking(true)
-            .unwrap_or_else(|e| log_panic!("Failed to set listener to nonblocking mode, reason:\n{reason}", reason = e.to_string()));
+    fn try_start_blocking(&self) -> io::Result<()> {
+        let listeners = self.bind_listeners()?;
 
+        thread::scope(|scope| {
+            listeners.into_iter().enumerate().for_each(|(id, listeners)| {
+                thread::Builder::new()
+                    .name(format!("acceptor-{id}"))
+                    .spawn_scoped(scope, move || self.accept_loop(listeners))
+                    .unwrap_or_else(|e| log_panic!("Failed to spawn acceptor thread, reason:\n{reason}", reason = e.to_string()));
+            })
+        });
+        Ok(())
+    }
+
+    fn shutdown_gracefully(self) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        self.workers.poison_all()
+    }
+
+    fn shutdown_gracefully_timeout(self, timeout: Duration) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        self.workers.poison_all_timeout(timeout);
+    }
+
+    fn builder() -> AsyncHttpServerBuilder {
+        AsyncHttpServerBuilder::default()
+    }
+}
+
+impl AsyncHttpServer {
+    fn accept_loop(&self, listeners: Vec<TcpListener>) {
         let epoll = epoll::create(false).unwrap_or_else(|e| log_panic!("Failed to create epoll, reason:\n{reason}", reason = e.to_string()));
         // https://stackoverflow.com/questions/31357215/is-it-ok-to-share-the-same-epoll-file-descriptor-among-threads
-        // To add multithreading: EPOLLIN | EPOLLET
-        let event = Event::new(Events::EPOLLIN | Events::EPOLLOUT, listener.as_raw_fd() as _);
-        epoll::ctl(epoll, EPOLL_CTL_ADD, listener.as_raw_fd(), event).unwrap_or_else(|e| panic!("Failed to register interested in epoll fd, reason:\n{e}"));
+        // Every acceptor owns its epoll instance, connections accepted here are only ever polled here. The listeners are shared by all
+        // acceptors, exclusive so a new connection wakes one of them instead of every one racing to accept it.
+        for listener in &listeners {
+            let event = Event::new(Events::EPOLLIN | Events::EPOLLEXCLUSIVE, listener.as_raw_fd() as _);
+            epoll::ctl(epoll, EPOLL_CTL_ADD, listener.as_raw_fd(), event).unwrap_or_else(|e| panic!("Failed to register interested in epoll fd, reason:\n{e}"));
+        }
 
-        // To add multithreading: spawn a new thread around here
         // events arr cannot be shared between threads, would be hard in rust anyway :D
+        let mut next_sweep = Instant::now() + SWEEP_INTERVAL;
         loop {
-            if self.shutdown_requested.load(Ordering::SeqCst) {
+            if self.should_stop() {
                 return;
             }
-            self.started.store(true, std::sync::atomic::Ordering::SeqCst);
+            self.mark_started();
 
             let mut events = [Event::new(Events::empty(), 0); 1024];
-            let num_events = epoll::wait(epoll, -1 /* block forever */, &mut events).unwrap_or_else(|e| log_panic!("IO error, reason:\n{reason}", reason = e.to_string()));
+            let num_events = match epoll::wait(epoll, POLL_TIMEOUT.as_millis() as i32, &mut events) {
+                Ok(n) => n,
+                // a signal arrived, might be the one asking us to stop
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => log_panic!("IO error, reason:\n{reason}", reason = e.to_string()),
+            };
+            if Instant::now() >= next_sweep {
+                self.sweep_idle_connections();
+                next_sweep = Instant::now() + SWEEP_INTERVAL;
+            }
 
             for event in &events[..num_events] {
-                let fd = event.data as i32;
+                let id = event.data;
+                let fd = id as i32;
 
-                if fd == listener.as_raw_fd() {
+                if let Some(listener) = listeners.iter().find(|listener| listener.as_raw_fd() as u64 == id) {
                     match listener.accept() {
                         Ok((connection, _)) => {
                             connection.set_nonblocking(true).expect("Failed to set connection to nonblocking mode.");
 
                             let fd = connection.as_raw_fd();
-
-                            let event = Event::new(Events::EPOLLIN | Events::EPOLLOUT, fd as _);
-                            epoll::ctl(epoll, EPOLL_CTL_ADD, fd, event).expect("Failed to register interest in connection events.");
+                            let id = self.conn_id(fd);
 
                             let state = ConnState::Read(Vec::new(), 0);
+                            let event = Event::new(Self::interest(&state), id);
+                            epoll::ctl(epoll, EPOLL_CTL_ADD, fd, event).expect("Failed to register interest in connection events.");
 
-                            self.connections.lock().expect("locking problem").insert(fd, (connection, state));
+                            self.connections.lock().expect("locking problem").insert(id, (connection, state, Instant::now()));
                         }
                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
                         Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
                         // do we wanna die here?
                         Err(e) => panic!("failed to accept: {}", e),
                     }
+                } else if Events::from_bits_truncate(event.events).intersects(Events::EPOLLERR | Events::EPOLLHUP) {
+                    // reset or closed in both directions, nothing can be read or written anymore. Dropping the socket deregisters it.
+                    // A worker busy with it finds out on its own.
+                    debug!("Connection {fd} errored or hung up, dropping it");
+                    self.connections.lock().expect("Poisoned").remove(&id);
                 } else {
                     let conns = self.connections.clone();
 
-                    let option = conns.lock().expect("Poisoned").remove(&fd);
-                    let deps_map = self.deps_map.clone();
-                    if let Some((conn, conn_status)) = option {
-                        let endpoint = self.endpoints.clone();
+                    let option = conns.lock().expect("Poisoned").remove(&id);
+                    let deps_map = self.deps_snapshot();
+                    let limits = self.limits;
+                    let propagate_panics = self.propagate_panics;
+                    if let Some((conn, conn_status, last_active)) = option {
+                        let endpoint = self.endpoints_snapshot();
+                        let fallback = self.fallback.clone();
+                        let default_headers = self.default_headers.clone();
+                        let close_timeout = self.close_timeout;
                         self.workers
                             .queue(async move {
-                                if let Some((conn, new_state)) = AsyncHandler::handle_async_better(conn, &conn_status, endpoint, deps_map).await {
-                                    if new_state != ConnState::Flush {
-                                        conns.lock().expect("Poisoned").insert(fd, (conn, new_state));
+                                // a connection that has been draining is dropped once it is done, not half closed again
+                                let close_timeout = close_timeout.filter(|_| !matches!(conn_status, ConnState::Closing(_)));
+                                let progress = conn_status.progress();
+                                if let Some((conn, new_state)) = AsyncHandler::handle_async_better(conn, conn_status, endpoint, fallback, deps_map, limits, default_headers, propagate_panics).await {
+                                    let next = if new_state != ConnState::Flush {
+                                        Some((AsyncHttpServer::last_active(progress, &new_state, last_active), new_state))
                                     } else {
-                                        drop(conn)
+                                        close_timeout
+                                            .and_then(|timeout| AsyncHttpServer::closing_state(&conn, timeout))
+                                            .map(|closing| (Instant::now(), closing))
+                                    };
+                                    // without the interest it needs the connection would never get another event, it is dropped instead
+                                    if let Some((last_active, state)) = next {
+                                        match AsyncHttpServer::update_interest(epoll, fd, id, &state) {
+                                            Ok(()) => {
+                                                conns.lock().expect("Poisoned").insert(id, (conn, state, last_active));
+                                            }
+                                            Err(e) => error!("Could not update interest in connection {fd}, dropping it: {e}"),
+                                        }
                                     }
                                 }
                             })
@@ -80,12 +144,16 @@ impl AsyncHttpServerTrt for AsyncHttpServer {
         }
     }
 
-    fn shutdown_gracefully(self) {
-        self.shutdown_requested.store(true, Ordering::SeqCst);
-        self.workers.poison_all()
+    fn interest(state: &ConnState) -> Events {
+        if state.awaits_write() {
+            Events::EPOLLIN | Events::EPOLLOUT
+        } else {
+            Events::EPOLLIN
+        }
     }
 
-    fn builder() -> AsyncHttpServerBuilder {
-        AsyncHttpServerBuilder::default()
+    // Before the connection goes back into the map, so no event for it gets missed in between
+    fn update_interest(epoll: i32, fd: i32, id: u64, state: &ConnState) -> io::Result<()> {
+        epoll::ctl(epoll, EPOLL_CTL_MOD, fd, Event::new(Self::interest(state), id))
     }
 }
@@ -0,0 +1,134 @@
+use super::async_handler::AsyncHandler;
+use super::async_http_server::{AsyncHttpServer, AsyncHttpServerBuilder, AsyncHttpServerTrt, POLL_TIMEOUT, SWEEP_INTERVAL};
+use super::ConnState;
+use crate::log_panic;
+use log::{debug, error};
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Fallback for unix targets without epoll or kqueue support here (illumos, the other BSDs, ...), also picked with the poll_reactor feature.
+// poll(2) keeps no registrations, every round polls whatever sits in the connections map.
+impl AsyncHttpServerTrt for AsyncHttpServer {
+    fn try_start_blocking(&self) -> io::Result<()> {
+        let listeners = self.bind_listeners()?;
+
+        thread::scope(|scope| {
+            listeners.into_iter().enumerate().for_each(|(id, listeners)| {
+                thread::Builder::new()
+                    .name(format!("acceptor-{id}"))
+                    .spawn_scoped(scope, move || self.accept_loop(listeners))
+                    .unwrap_or_else(|e| log_panic!("Failed to spawn acceptor thread, reason:\n{reason}", reason = e.to_string()));
+            })
+        });
+        Ok(())
+    }
+
+    fn shutdown_gracefully(self) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        self.workers.poison_all()
+    }
+
+    fn shutdown_gracefully_timeout(self, timeout: Duration) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+        self.workers.poison_all_timeout(timeout);
+    }
+
+    fn builder() -> AsyncHttpServerBuilder {
+        AsyncHttpServerBuilder::default()
+    }
+}
+
+impl AsyncHttpServer {
+    // With several acceptors they all poll every connection, whichever takes it out of the map first handles the event
+    fn accept_loop(&self, listeners: Vec<TcpListener>) {
+        let mut next_sweep = Instant::now() + SWEEP_INTERVAL;
+        loop {
+            if self.should_stop() {
+                return;
+            }
+            self.mark_started();
+
+            // same interest as the epoll one, writable only counts for a connection with something to write
+            let (ids, mut poll_fds): (Vec<u64>, Vec<libc::pollfd>) = listeners
+                .iter()
+                .map(|listener| (listener.as_raw_fd() as u64, listener.as_raw_fd(), libc::POLLIN))
+                .chain(self.connections.lock().expect("Poisoned").iter().map(|(&id, (conn, state, _))| {
+                    let events = if state.awaits_write() { libc::POLLIN | libc::POLLOUT } else { libc::POLLIN };
+                    (id, conn.as_raw_fd(), events)
+                }))
+                .map(|(id, fd, events)| (id, libc::pollfd { fd, events, revents: 0 }))
+                .unzip();
+            let num_events = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, POLL_TIMEOUT.as_millis() as libc::c_int) };
+            if num_events == -1 {
+                let e = io::Error::last_os_error();
+                // a signal arrived, might be the one asking us to stop
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log_panic!("IO error, reason:\n{reason}", reason = e.to_string());
+            }
+            if Instant::now() >= next_sweep {
+                self.sweep_idle_connections();
+                next_sweep = Instant::now() + SWEEP_INTERVAL;
+            }
+
+            // by id, the fd may have been closed and handed to a new connection since the snapshot, see conn_id
+            for (&id, poll_fd) in ids.iter().zip(&poll_fds).filter(|(_, poll_fd)| poll_fd.revents != 0) {
+                let fd = poll_fd.fd;
+
+                if let Some(listener) = listeners.iter().find(|listener| listener.as_raw_fd() as u64 == id) {
+                    match listener.accept() {
+                        Ok((connection, _)) => {
+                            connection.set_nonblocking(true).expect("Failed to set connection to nonblocking mode.");
+                            let state = ConnState::Read(Vec::new(), 0);
+                            let id = self.conn_id(connection.as_raw_fd());
+                            self.connections.lock().expect("locking problem").insert(id, (connection, state, Instant::now()));
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                        Err(e) if e.kind() == io::ErrorKind::InvalidInput => continue,
+                        // do we wanna die here?
+                        Err(e) => panic!("failed to accept: {}", e),
+                    }
+                } else if poll_fd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                    // reset or closed in both directions, see the epoll one
+                    debug!("Connection {fd} errored or hung up, dropping it");
+                    self.connections.lock().expect("Poisoned").remove(&id);
+                } else {
+                    let conns = self.connections.clone();
+
+                    let option = conns.lock().expect("Poisoned").remove(&id);
+                    let deps_map = self.deps_snapshot();
+                    let limits = self.limits;
+                    let propagate_panics = self.propagate_panics;
+                    if let Some((conn, conn_status, last_active)) = option {
+                        let endpoint = self.endpoints_snapshot();
+                        let fallback = self.fallback.clone();
+                        let default_headers = self.default_headers.clone();
+                        let close_timeout = self.close_timeout;
+                        self.workers
+                            .queue(async move {
+                                // a connection that has been draining is dropped once it is done, not half closed again
+                                let close_timeout = close_timeout.filter(|_| !matches!(conn_status, ConnState::Closing(_)));
+                                let progress = conn_status.progress();
+                                if let Some((conn, new_state)) = AsyncHandler::handle_async_better(conn, conn_status, endpoint, fallback, deps_map, limits, default_headers, propagate_panics).await {
+                                    if new_state != ConnState::Flush {
+                                        let last_active = AsyncHttpServer::last_active(progress, &new_state, last_active);
+                                        conns.lock().expect("Poisoned").insert(id, (conn, new_state, last_active));
+                                    } else if let Some(closing) = close_timeout.and_then(|timeout| AsyncHttpServer::closing_state(&conn, timeout)) {
+                                        conns.lock().expect("Poisoned").insert(id, (conn, closing, Instant::now()));
+                                    } else {
+                                        drop(conn)
+                                    }
+                                }
+                            })
+                            .unwrap_or_else(|e| error!("Failed to queue async job: {e}"));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -17,7 +17,7 @@ pub struct HttpServer {
 
 pub trait HttpServerTrt {
     fn create_addr(addr: &str, endpoints: HashSet<Handler>) -> HttpServer;
-    fn create_port(port: u32, endpoints: HashSet<Handler>) -> HttpServer;
+    fn create_port(port: u16, endpoints: HashSet<Handler>) -> HttpServer;
     fn start_blocking(&self);
 }
 
@@ -32,10 +32,7 @@ impl HttpServerTrt for HttpServer {
         HttpServer { endpoints, workers, listener }
     }
 
-    fn create_port(port: u32, endpoints: HashSet<Handler>) -> HttpServer {
-        if port > 65535 {
-            log_panic!("Port cannot be higher than 65535, was: {port}")
-        }
+    fn create_port(port: u16, endpoints: HashSet<Handler>) -> HttpServer {
         let thread_count = thread::available_parallelism().unwrap().get();
         let endpoints = endpoints.into_iter().map(|x| (x.gen_key(), x)).collect();
         let workers = Workers::new(thread_count);
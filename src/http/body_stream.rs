@@ -0,0 +1,75 @@
+use super::{parse_error::ParseError, AsyncRequest, Error};
+
+// Upper bound for a piece of a Content-Length body, chunked bodies are yielded chunk by chunk
+const MAX_PIECE_SIZE: usize = 16 * 1024;
+
+pub(crate) enum BodyFraming {
+    Length(usize),
+    Chunked,
+}
+
+// See AsyncRequest::body_stream
+pub struct BodyStream<'a> {
+    req: &'a mut AsyncRequest,
+    // None once the body has been read to the end, or reading it failed
+    remaining: Option<Remaining>,
+    read: usize,
+    // the start of the body, see with_body_logging
+    logged: Vec<u8>,
+}
+
+enum Remaining {
+    Bytes(usize),
+    Chunks,
+}
+
+impl<'a> BodyStream<'a> {
+    pub(crate) fn new(req: &'a mut AsyncRequest) -> Result<BodyStream<'a>, Error> {
+        let remaining = match req.body_framing()? {
+            BodyFraming::Length(content_length) if content_length > req.limits.max_body_size => return Err(ParseError::BodyTooLarge.into()),
+            BodyFraming::Length(content_length) => Remaining::Bytes(content_length),
+            BodyFraming::Chunked => Remaining::Chunks,
+        };
+        req.send_continue()?;
+        Ok(BodyStream {
+            req,
+            remaining: Some(remaining),
+            read: 0,
+            logged: Vec::new(),
+        })
+    }
+
+    // None once the whole body (and for a chunked one, its trailers) has been read. Nothing more comes after an error.
+    pub async fn next(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        let piece = match self.remaining.as_mut()? {
+            Remaining::Bytes(0) => Ok(None),
+            Remaining::Bytes(left) => self.req.read_body_some((*left).min(MAX_PIECE_SIZE)).map(|piece| {
+                *left -= piece.len();
+                Some(piece)
+            }),
+            Remaining::Chunks => self.req.read_chunk(),
+        };
+        let max_body_size = self.req.limits.max_body_size;
+        let piece = piece.and_then(|piece| match piece {
+            Some(piece) if self.read + piece.len() > max_body_size => Err(ParseError::BodyTooLarge.into()),
+            piece => Ok(piece),
+        });
+        match piece {
+            Ok(Some(piece)) => {
+                self.read += piece.len();
+                let missing = self.req.limits.logged_body_bytes.saturating_sub(self.logged.len());
+                self.logged.extend_from_slice(&piece[..missing.min(piece.len())]);
+                Some(Ok(piece))
+            }
+            Ok(None) => {
+                self.remaining = None;
+                self.req.log_body(&self.logged, self.read);
+                None
+            }
+            Err(e) => {
+                self.remaining = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+// Path pattern split into segments once at registration, e.g. `/users/:id` -> [Static(""), Static("users"), Param("id")]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CompiledPath {
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+impl CompiledPath {
+    pub fn compile(pattern: &str) -> CompiledPath {
+        let segments = pattern
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Static(segment.to_string()),
+            })
+            .collect();
+        CompiledPath {
+            pattern: pattern.to_string(),
+            segments,
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let split_path = path.split('/').collect::<Vec<&str>>();
+        split_path.len() == self.segments.len()
+            && self.segments.iter().zip(split_path).all(|(segment, part)| match segment {
+                Segment::Static(s) => s == part,
+                Segment::Param(_) => true,
+            })
+    }
+
+    // Expects a path that `matches`, parameters of a non matching path are meaningless
+    pub fn extract_params(&self, path: &str) -> HashMap<String, String> {
+        self.segments
+            .iter()
+            .zip(path.split('/'))
+            .filter_map(|(segment, part)| match segment {
+                Segment::Param(name) => Some((name.clone(), part.to_string())),
+                Segment::Static(_) => None,
+            })
+            .collect()
+    }
+
+    // Normalized form, routes that differ only in parameter names share the same key
+    pub fn pattern_key(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Static(s) => s.as_str(),
+                Segment::Param(_) => ":param",
+            })
+            .collect::<Vec<&str>>()
+            .join("/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::CompiledPath;
+
+    #[test]
+    fn matches_static_and_param_segments() {
+        let compiled = CompiledPath::compile("/users/:id/posts");
+
+        assert!(compiled.matches("/users/42/posts"));
+        assert!(!compiled.matches("/users/42"));
+        assert!(!compiled.matches("/users/42/comments"));
+    }
+
+    #[test]
+    fn extracts_params() {
+        let compiled = CompiledPath::compile("/users/:id/posts/:post");
+
+        assert_eq!(
+            compiled.extract_params("/users/42/posts/7"),
+            HashMap::from([("id".to_string(), "42".to_string()), ("post".to_string(), "7".to_string())])
+        );
+    }
+
+    #[test]
+    fn pattern_is_preserved_as_registered() {
+        assert_eq!(CompiledPath::compile("/a/:b/c").pattern(), "/a/:b/c");
+    }
+
+    #[test]
+    fn pattern_key_ignores_param_names() {
+        assert_eq!(CompiledPath::compile("/users/:id").pattern_key(), "/users/:param");
+        assert_eq!(CompiledPath::compile("/users/:id").pattern_key(), CompiledPath::compile("/users/:name").pattern_key());
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::Read;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+use super::{parse_error::ParseError, Error};
+
+// https://www.rfc-editor.org/rfc/rfc9110#section-8.4 - what a request body can be encoded with, identity means not at all
+const SUPPORTED: [&str; 4] = ["gzip", "x-gzip", "deflate", "identity"];
+
+// A Content-Encoding value, e.g. `deflate, gzip` -> deflate applied first
+fn codings(content_encoding: &str) -> impl DoubleEndedIterator<Item = &str> {
+    content_encoding.split(',').map(str::trim).filter(|coding| !coding.is_empty())
+}
+
+// Unknown codings get a 415 before any of the body is read
+pub fn check_supported(content_encoding: &str) -> Result<(), Error> {
+    match codings(content_encoding).find(|encoding| !SUPPORTED.iter().any(|supported| encoding.eq_ignore_ascii_case(supported))) {
+        Some(_) => Err(Error::new(415, "Unsupported Content-Encoding")),
+        None => Ok(()),
+    }
+}
+
+// Undoes the codings in the reverse order they were applied. The decoded body counts against `max_size` as it is inflated,
+// so a small bomb never gets to allocate more than that.
+pub fn decode(content_encoding: &str, body: Vec<u8>, max_size: usize) -> Result<Vec<u8>, Error> {
+    check_supported(content_encoding)?;
+    codings(content_encoding).rev().try_fold(body, |body, encoding| {
+        let decoder: Box<dyn Read> = match encoding.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Box::new(GzDecoder::new(body.as_slice())),
+            // https://www.rfc-editor.org/rfc/rfc9110#section-8.4.1.2 - deflate means the zlib format
+            "deflate" => Box::new(ZlibDecoder::new(body.as_slice())),
+            _ => return Ok(body),
+        };
+        let mut decoded = Vec::new();
+        decoder
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|e| Error::new_with_desc(400, "Body could not be decoded", &e.to_string()))?;
+        if decoded.len() > max_size {
+            return Err(ParseError::BodyTooLarge.into());
+        }
+        Ok(decoded)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    use super::{check_supported, decode};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn codings_are_undone_in_reverse_order() {
+        let mut deflated = ZlibEncoder::new(Vec::new(), Compression::default());
+        deflated.write_all(b"hello").unwrap();
+        let body = gzip(&deflated.finish().unwrap());
+
+        assert_eq!(decode("deflate, GZIP", body, 1024).unwrap(), b"hello");
+        assert_eq!(decode("identity", b"hello".to_vec(), 1024).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn unknown_and_broken_bodies_are_rejected() {
+        assert_eq!(check_supported("gzip, br").unwrap_err().status_code, 415);
+        assert_eq!(decode("gzip", b"not gzip".to_vec(), 1024).unwrap_err().status_code, 400);
+    }
+
+    #[test]
+    fn inflating_past_the_limit_stops_early() {
+        let bomb = gzip(&vec![0; 10 * 1024 * 1024]);
+        assert!(bomb.len() < 16 * 1024);
+
+        assert_eq!(decode("gzip", bomb, 1024 * 1024).unwrap_err().status_code, 413);
+    }
+}
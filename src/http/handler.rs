@@ -26,7 +26,7 @@ impl Handler {
     {
         let request = Request::create(path.as_str(), Self::not_found("fix_me"), HashMap::new());
         let res = (self.handler_func)(&request)?; // TODO[FL]: return 500 Internal somehow
-        let status_code = res.status_code;
+        let status_code = res.status_code.as_u16();
         let status_line = res.get_status_line();
         let contents = res.response_body;
         let length = contents.len();
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use super::{parse_error::ParseError, Error};
+
+// Header names are case insensitive, lookups go through the lowercased name. Names and values are kept as received (trimmed).
+// Iteration follows insertion order, replacing a header keeps its position.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+    // lowercased name -> position in `entries`
+    index: HashMap<String, usize>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    // Lenient variant: obs-fold continuation lines are unfolded into the previous header, lines without a colon are dropped
+    pub fn from_lines<'a>(lines: impl IntoIterator<Item = &'a str>) -> Headers {
+        let mut headers = Headers::new();
+        let mut last_name: Option<String> = None;
+        lines.into_iter().for_each(|line| {
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = last_name.as_ref().and_then(|name| headers.index.get(name)).map(|&i| &mut headers.entries[i]) {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim(), value.trim());
+                last_name = Some(name.trim().to_lowercase());
+            } else {
+                last_name = None;
+            }
+        });
+        headers
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7230#section-3.2.4 - obs-fold and malformed lines are rejected with a 400, so are repeated
+    // Content-Length headers that disagree (https://www.rfc-editor.org/rfc/rfc9112#section-6.3). Insert keeps only the last one, a proxy
+    // going by the first would frame the body differently.
+    pub fn try_from_lines<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<Headers, Error> {
+        lines.into_iter().try_fold(Headers::new(), |mut headers, line| {
+            if line.starts_with([' ', '\t']) {
+                return Err(ParseError::ObsoleteLineFolding.into());
+            }
+            let (name, value) = line.split_once(':').ok_or(ParseError::MalformedHeaderLine)?;
+            if name.is_empty() || name.ends_with([' ', '\t']) {
+                return Err(ParseError::MalformedHeaderName.into());
+            }
+            if name.eq_ignore_ascii_case("content-length") && headers.get(name).is_some_and(|length| length != value.trim()) {
+                return Err(ParseError::ConflictingContentLength.into());
+            }
+            headers.insert(name, value.trim());
+            Ok(headers)
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.index.get(&name.to_lowercase()).map(|&i| self.entries[i].1.as_str())
+    }
+
+    pub fn insert(&mut self, name: &str, value: &str) {
+        let entry = (name.to_string(), value.to_string());
+        match self.index.get(&name.to_lowercase()) {
+            Some(&i) => self.entries[i] = entry,
+            None => {
+                self.index.insert(name.to_lowercase(), self.entries.len());
+                self.entries.push(entry);
+            }
+        }
+    }
+
+    // Comma separated list values, trimmed and without empty elements, e.g. `Connection: keep-alive, Upgrade`
+    pub fn tokens(&self, name: &str) -> Vec<&str> {
+        self.get(name)
+            .map_or_else(Vec::new, |value| value.split(',').map(str::trim).filter(|token| !token.is_empty()).collect())
+    }
+
+    pub fn content_type(&self) -> Option<MediaType<'_>> {
+        self.get("content-type").map(MediaType::parse)
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc9110#section-12.5.1 - the candidate the Accept header ranks highest, ties go to the earlier
+    // candidate. Only named types and `type/*` ranges count, None when the client accepts none of them or anything (`*/*`) equally.
+    pub fn preferred_media_type<'c>(&self, candidates: &[&'c str]) -> Option<&'c str> {
+        let ranges = self.tokens("accept").into_iter().map(MediaType::parse).collect::<Vec<_>>();
+        let quality_of = |candidate: &str| {
+            let (kind, _) = candidate.split_once('/').unwrap_or((candidate, ""));
+            let exact = ranges.iter().find(|range| range.is(candidate));
+            let any_subtype = ranges.iter().find(|range| {
+                range
+                    .essence()
+                    .split_once('/')
+                    .is_some_and(|(range_kind, subtype)| subtype == "*" && range_kind.eq_ignore_ascii_case(kind))
+            });
+            exact.or(any_subtype).map(|range| range.param("q").and_then(|q| q.parse::<f32>().ok()).unwrap_or(1.0))
+        };
+        candidates
+            .iter()
+            .filter_map(|&candidate| quality_of(candidate).filter(|&q| q > 0.0).map(|q| (candidate, q)))
+            .fold(None, |best: Option<(&'c str, f32)>, (candidate, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((candidate, q)),
+            })
+            .map(|(candidate, _)| candidate)
+    }
+
+    pub fn authorization(&self) -> Option<&str> {
+        self.get("authorization")
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.get("host")
+    }
+
+    pub fn connection_tokens(&self) -> Vec<&str> {
+        self.tokens("connection")
+    }
+
+    // Whether a list header names `token`, compared case insensitively
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.tokens(name).iter().any(|t| t.eq_ignore_ascii_case(token))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(&name.to_lowercase())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// A Content-Type value, e.g. `text/html; charset="utf-8"` -> essence `text/html` with the charset parameter `utf-8`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MediaType<'a> {
+    essence: &'a str,
+    params: &'a str,
+}
+
+impl<'a> MediaType<'a> {
+    pub fn parse(value: &'a str) -> MediaType<'a> {
+        let (essence, params) = value.split_once(';').unwrap_or((value, ""));
+        MediaType { essence: essence.trim(), params }
+    }
+
+    // type/subtype as sent, see is for comparing
+    pub fn essence(&self) -> &'a str {
+        self.essence
+    }
+
+    // Media types are case insensitive, parameters are ignored
+    pub fn is(&self, essence: &str) -> bool {
+        self.essence.eq_ignore_ascii_case(essence)
+    }
+
+    pub fn param(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .split(';')
+            .filter_map(|param| param.split_once('='))
+            .find(|(param_name, _)| param_name.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().trim_matches('"'))
+    }
+}
+
+// Order does not matter for equality, only which headers there are
+impl PartialEq for Headers {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.entries.iter().all(|entry| other.index.get(&entry.0.to_lowercase()).is_some_and(|&i| other.entries[i] == *entry))
+    }
+}
+
+impl Eq for Headers {}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::parse_error::ParseError;
+
+    use super::{Headers, MediaType};
+
+    #[test]
+    fn names_are_case_insensitive_and_values_are_kept() {
+        let headers = Headers::try_from_lines(["Content-Type: Application/JSON", "X-Token:  AbC "]).unwrap();
+
+        assert_eq!(headers.get("content-type"), Some("Application/JSON"));
+        assert_eq!(headers.get("X-TOKEN"), Some("AbC"));
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn iteration_follows_insertion_order() {
+        let mut headers = Headers::new();
+        headers.insert("X-B", "1");
+        headers.insert("X-A", "2");
+        headers.insert("X-C", "3");
+        headers.insert("x-a", "4");
+
+        assert_eq!(headers.iter().collect::<Vec<_>>(), vec![("X-B", "1"), ("x-a", "4"), ("X-C", "3")]);
+    }
+
+    #[test]
+    fn try_from_lines_rejects_folded_header() {
+        let err = Headers::try_from_lines(["X-Long: first", " second"]).unwrap_err();
+
+        assert_eq!(err.status_code, 400);
+    }
+
+    #[test]
+    fn try_from_lines_rejects_differing_content_lengths() {
+        let err = Headers::try_from_lines(["Content-Length: 10", "content-length: 0"]).unwrap_err();
+        let repeated = Headers::try_from_lines(["Content-Length: 10", "Content-Length: 10"]).unwrap();
+
+        assert_eq!(err.parse_error, Some(ParseError::ConflictingContentLength));
+        assert_eq!(err.status_code, 400);
+        assert_eq!(repeated.get("content-length"), Some("10"));
+    }
+
+    #[test]
+    fn try_from_lines_rejects_line_without_colon() {
+        let err = Headers::try_from_lines(["Host: localhost", "no colon here"]).unwrap_err();
+
+        assert_eq!(err.status_code, 400);
+    }
+
+    #[test]
+    fn from_lines_unfolds_folded_header_and_drops_line_without_colon() {
+        let headers = Headers::from_lines(["X-Long: first", "\tsecond", "no colon here", "Host: localhost"]);
+
+        assert_eq!(headers.get("x-long"), Some("first second"));
+        assert_eq!(headers.get("host"), Some("localhost"));
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn content_type_splits_off_parameters() {
+        let headers = Headers::from_lines(["Content-Type: Text/HTML ; Charset=\"utf-8\"; boundary=x"]);
+        let content_type = headers.content_type().unwrap();
+
+        assert_eq!(content_type.essence(), "Text/HTML");
+        assert!(content_type.is("text/html"));
+        assert_eq!(content_type.param("charset"), Some("utf-8"));
+        assert_eq!(content_type.param("boundary"), Some("x"));
+        assert_eq!(content_type.param("missing"), None);
+        assert_eq!(MediaType::parse("application/json").param("charset"), None);
+        assert_eq!(Headers::new().content_type(), None);
+    }
+
+    #[test]
+    fn authorization_and_host_are_plain_lookups() {
+        let headers = Headers::from_lines(["Authorization: Bearer abc", "Host: example.com:8080"]);
+
+        assert_eq!(headers.authorization(), Some("Bearer abc"));
+        assert_eq!(headers.host(), Some("example.com:8080"));
+        assert_eq!(Headers::new().authorization(), None);
+        assert_eq!(Headers::new().host(), None);
+    }
+
+    #[test]
+    fn connection_tokens_split_the_comma_list() {
+        let headers = Headers::from_lines(["Connection: keep-alive, , Upgrade ", "Transfer-Encoding: gzip,Chunked"]);
+
+        assert_eq!(headers.connection_tokens(), vec!["keep-alive", "Upgrade"]);
+        assert!(headers.has_token("connection", "upgrade"));
+        assert!(headers.has_token("transfer-encoding", "chunked"));
+        assert!(!headers.has_token("connection", "close"));
+        assert!(Headers::new().connection_tokens().is_empty());
+    }
+
+    #[test]
+    fn preferred_media_type_follows_accept_quality() {
+        let preferred = |accept: &str| Headers::from_lines([accept]).preferred_media_type(&["application/json", "text/html"]);
+
+        assert_eq!(preferred("Accept: application/json"), Some("application/json"));
+        assert_eq!(preferred("Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"), Some("text/html"));
+        assert_eq!(preferred("Accept: text/html;q=0.5, application/*"), Some("application/json"));
+        assert_eq!(preferred("Accept: text/html, application/json"), Some("application/json"));
+        assert_eq!(preferred("Accept: application/json;q=0, text/plain"), None);
+        assert_eq!(preferred("Accept: */*"), None);
+        assert_eq!(Headers::new().preferred_media_type(&["application/json"]), None);
+    }
+}
@@ -1,38 +1,269 @@
-use std::collections::HashMap;
-use log::debug;
-
-// TODO [FL]: write tests for these methods
-pub fn extract_path_params(pattern: &str, path: &str) -> HashMap<String, String> {
-    let split_pattern = pattern.split('/').collect::<Vec<&str>>();
-    let split_path = path.split('/').collect::<Vec<&str>>();
-
-    if split_pattern.len() != split_path.len() {
-        panic!("split_pattern.len() != split_path.len() - this should be done prior to calling this method")
-    }
-
-    (0..split_path.len())
-        .filter_map(|i| {
-            if split_pattern[i].starts_with(':') {
-                let mut chars = split_pattern[i].chars();
-                chars.next();
-                Some((chars.as_str().to_string(), split_path[i].to_string()))
-            } else {
-                None
-            }
-        })
-        .collect()
-}
-
-pub fn path_matches_pattern(pattern: &str, path: &str) -> bool {
-    let split_pattern = pattern.split('/').collect::<Vec<&str>>();
-    let split_path = path.split('/').collect::<Vec<&str>>();
-    debug!("split_pattern: {split_pattern:?}, split_path: {split_path:?}");
-    if split_pattern.len() != split_path.len() {
-        return false;
-    }
-
-    (0..split_path.len())
-        .map(|i| split_path[i] == split_pattern[i] || split_pattern[i].starts_with(':'))
-        .reduce(|acc, e| acc && e)
-        .unwrap()
-}
\ No newline at end of file
+use std::{io, str::from_utf8};
+
+use super::{headers::Headers, limits::Limits, parse_error::ParseError, uri::Uri, ConnStream, Error};
+
+const INITIAL_BUFFER_SIZE: usize = 8192;
+
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub headers: Headers,
+}
+
+// Why a request head could not be read. Rejected ones get answered with the error before the connection closes.
+#[derive(Debug)]
+pub enum HeadError {
+    Io(io::Error),
+    Rejected(Error),
+}
+
+impl From<io::Error> for HeadError {
+    fn from(e: io::Error) -> Self {
+        HeadError::Io(e)
+    }
+}
+
+pub fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+// Appends whatever the connection has to `buf` and returns the head size (including the empty line) once it is complete.
+// Only the head gets consumed, the body stays in the stream. Ok(None) means the head is not complete yet, call again when more data arrives.
+// `buf` is peeked into directly, so the same allocation is reused across calls and only grows when it is full.
+pub fn read_http_request<S: ConnStream>(connection: &mut S, buf: &mut Vec<u8>, limits: Limits) -> Result<Option<usize>, HeadError> {
+    if buf.capacity() == 0 {
+        buf.reserve_exact(INITIAL_BUFFER_SIZE);
+    } else if buf.len() == buf.capacity() {
+        buf.reserve(buf.len());
+    }
+
+    let already_read = buf.len();
+    buf.resize(buf.capacity(), 0);
+    let peeked = match connection.peek(&mut buf[already_read..]) {
+        Ok(n) => n,
+        Err(e) => {
+            buf.truncate(already_read);
+            return Err(e.into());
+        }
+    };
+    buf.truncate(already_read + peeked);
+    if peeked == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the request head was complete").into());
+    }
+
+    // the empty line may straddle the previous fragment and this one
+    let search_from = already_read.saturating_sub(3);
+    let head_size = find_head_end(&buf[search_from..]).map(|pos| search_from + pos + 4);
+    buf.truncate(head_size.unwrap_or(buf.len()));
+    // reads back exactly what has just been peeked
+    connection.read_exact(&mut buf[already_read..])?;
+    if head_size.is_none() && buf.len() >= limits.head_size_limit() {
+        return Err(HeadError::Rejected(ParseError::HeadTooLarge.into()));
+    }
+    Ok(head_size)
+}
+
+// Works on a possibly incomplete head, the target seen so far already counts
+pub fn check_uri_length(buf: &[u8], limits: Limits) -> Result<(), Error> {
+    let request_line = buf.split(|b| *b == b'\n').next().unwrap_or_default();
+    let uri_length = request_line.split(|b| *b == b' ').nth(1).map_or(0, <[u8]>::len);
+    if uri_length > limits.max_uri_length {
+        return Err(ParseError::UriTooLong.into());
+    }
+    Ok(())
+}
+
+// Lowercased, without the port and a trailing dot, so `API.example.com.:8080` and `api.example.com` are the same host
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.rfind(':') {
+        // an IPv6 literal has colons of its own, only one after the closing bracket starts a port
+        Some(port_start) if !host[port_start..].contains(']') => &host[..port_start],
+        _ => host,
+    };
+    host.trim_end_matches('.').to_lowercase()
+}
+
+// https://www.rfc-editor.org/rfc/rfc9110#section-10.1.1 - a client waiting for 100 Continue learns that its body is too large
+// before sending it. 100-continue is the only expectation there is.
+fn check_expectation(headers: &Headers, limits: Limits) -> Result<(), Error> {
+    let Some(expect) = headers.get("expect") else {
+        return Ok(());
+    };
+    if !expect.eq_ignore_ascii_case("100-continue") {
+        return Err(ParseError::UnsupportedExpectation.into());
+    }
+    if headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .is_some_and(|length| length > limits.max_body_size)
+    {
+        return Err(ParseError::BodyTooLarge.into());
+    }
+    Ok(())
+}
+
+// https://www.rfc-editor.org/rfc/rfc9112#section-3 - exactly `method SP request-target SP HTTP-version`. Extra or other whitespace is
+// rejected rather than tolerated, a lenient split is where a proxy and the server start to disagree about the request.
+fn tokenize_request_line(request_line: &str) -> Result<(&str, &str, &str), Error> {
+    let parts = request_line.split(' ').collect::<Vec<&str>>();
+    let [method, target, version] = parts[..] else {
+        return Err(ParseError::InvalidRequestLine.into());
+    };
+    // a leading space would otherwise leave an empty method that could only ever match a handler registered with one
+    if method.is_empty() {
+        return Err(ParseError::MissingMethod.into());
+    }
+    if !method.bytes().all(is_tchar) {
+        return Err(ParseError::BadMethod.into());
+    }
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+        return Err(ParseError::InvalidRequestLine.into());
+    }
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(ParseError::BadVersion.into());
+    }
+    Ok((method, target, version))
+}
+
+// The method a POST asks to be routed as. TRACE and CONNECT cannot be asked for, neither can anything that is not a method at all.
+pub fn overridden_method(head: &RequestHead) -> Option<String> {
+    if head.method != "POST" {
+        return None;
+    }
+    let method = head.headers.get("x-http-method-override")?.trim().to_ascii_uppercase();
+    if method.is_empty() || !method.bytes().all(is_tchar) || method == "TRACE" || method == "CONNECT" {
+        return None;
+    }
+    Some(method)
+}
+
+// https://www.rfc-editor.org/rfc/rfc9110#section-5.6.2
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// Works on raw bytes, anything that is not valid UTF-8 gets rejected instead of being silently replaced
+pub fn parse_request_head(head: &[u8], limits: Limits) -> Result<RequestHead, Error> {
+    let mut lines = head.split(|b| *b == b'\n').map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+
+    let request_line = from_utf8(lines.next().unwrap_or_default()).map_err(|_| ParseError::RequestLineNotUtf8)?;
+    let (method, target, version) = tokenize_request_line(request_line)?;
+    // raw bytes are already known to be UTF-8 here, what the escapes in the path decode to has to be as well
+    if Uri::parse(target).decoded_path().is_none() {
+        return Err(ParseError::TargetNotUtf8.into());
+    }
+
+    let lines = lines.collect::<Vec<&[u8]>>();
+    if lines.len() > limits.max_header_count {
+        return Err(ParseError::TooManyHeaders.into());
+    }
+    if lines.iter().any(|line| line.len() > limits.max_header_line_length) {
+        return Err(ParseError::HeaderTooLong.into());
+    }
+
+    let lines = lines
+        .into_iter()
+        .map(|line| from_utf8(line).map_err(|_| Error::from(ParseError::HeaderNotUtf8)))
+        .collect::<Result<Vec<&str>, Error>>()?;
+    let headers = Headers::try_from_lines(lines)?;
+    // https://www.rfc-editor.org/rfc/rfc9112#section-3.2 - HTTP/1.1 requests must name the host, HTTP/1.0 ones may not know about it
+    if version == "HTTP/1.1" && !headers.contains("host") {
+        return Err(ParseError::MissingHost.into());
+    }
+    // https://www.rfc-editor.org/rfc/rfc9112#section-6.3 - a server going by one and a proxy by the other disagree on where the next
+    // request starts
+    if headers.contains("content-length") && headers.contains("transfer-encoding") {
+        return Err(ParseError::ContentLengthWithTransferEncoding.into());
+    }
+    check_expectation(&headers, limits)?;
+
+    Ok(RequestHead {
+        method: method.to_string(),
+        path: target.to_string(),
+        protocol: version.to_string(),
+        headers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::limits::Limits;
+    use crate::http::parse_error::ParseError;
+
+    use super::{normalize_host, overridden_method, parse_request_head};
+
+    fn rejection(head: &str) -> Option<(u16, String)> {
+        parse_request_head(head.as_bytes(), Limits::default()).err().map(|e| (e.status_code, e.title))
+    }
+
+    #[test]
+    fn request_line_needs_single_spaces_and_a_known_version() {
+        assert_eq!(rejection("GET /a HTTP/1.1\r\nHost: localhost"), None);
+        assert_eq!(rejection("GET /a HTTP/1.0"), None);
+        assert_eq!(rejection("GET  /a HTTP/1.1\r\nHost: localhost"), Some((400, "Malformed request line".to_string())));
+        assert_eq!(rejection("GET /a  HTTP/1.1\r\nHost: localhost"), Some((400, "Malformed request line".to_string())));
+        assert_eq!(rejection("GET\t/a\tHTTP/1.1\r\nHost: localhost"), Some((400, "Malformed request line".to_string())));
+        assert_eq!(rejection("GET /a\r\nHost: localhost"), Some((400, "Malformed request line".to_string())));
+        assert_eq!(rejection("GET /a HTTP/1.1 \r\nHost: localhost"), Some((400, "Malformed request line".to_string())));
+        assert_eq!(rejection("GET /a HTTP/2.0\r\nHost: localhost"), Some((400, "Invalid HTTP version".to_string())));
+        assert_eq!(rejection("GET /a http/1.1\r\nHost: localhost"), Some((400, "Invalid HTTP version".to_string())));
+    }
+
+    #[test]
+    fn malformed_heads_carry_their_parse_error() {
+        let limits = Limits {
+            max_header_line_length: 32,
+            ..Limits::default()
+        };
+        let parse_error_of = |head: &str| parse_request_head(head.as_bytes(), limits).err().map(|e| (e.parse_error, e.status_code));
+
+        assert_eq!(parse_error_of("GET /a HTTP/2.0\r\nHost: localhost"), Some((Some(ParseError::BadVersion), 400)));
+        assert_eq!(parse_error_of("GE(T /a HTTP/1.1\r\nHost: localhost"), Some((Some(ParseError::BadMethod), 400)));
+        assert_eq!(parse_error_of("GET /a HTTP/1.1\r\nAccept: */*"), Some((Some(ParseError::MissingHost), 400)));
+        assert_eq!(
+            parse_error_of("GET /a HTTP/1.1\r\nHost: localhost\r\nX-Long: 0123456789012345678901234567890"),
+            Some((Some(ParseError::HeaderTooLong), 431))
+        );
+        assert_eq!(
+            parse_error_of("GET /a HTTP/1.1\r\nHost: localhost\r\nExpect: later"),
+            Some((Some(ParseError::UnsupportedExpectation), 417))
+        );
+        assert_eq!(parse_error_of("GET /a HTTP/1.1\r\nHost: localhost\r\n folded"), Some((Some(ParseError::ObsoleteLineFolding), 400)));
+        assert_eq!(
+            parse_error_of("POST /a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\nContent-Length: 0"),
+            Some((Some(ParseError::ConflictingContentLength), 400))
+        );
+        assert_eq!(
+            parse_error_of("POST /a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\nTransfer-Encoding: chunked"),
+            Some((Some(ParseError::ContentLengthWithTransferEncoding), 400))
+        );
+    }
+
+    #[test]
+    fn method_has_to_be_a_token() {
+        assert_eq!(rejection(" /a HTTP/1.1\r\nHost: localhost"), Some((400, "Missing request method".to_string())));
+        assert_eq!(rejection("GE(T /a HTTP/1.1\r\nHost: localhost"), Some((400, "Invalid request method".to_string())));
+        assert_eq!(rejection("M-SEARCH * HTTP/1.1\r\nHost: localhost"), None);
+    }
+
+    #[test]
+    fn only_a_post_can_override_its_method() {
+        let overridden = |head: &str| overridden_method(&parse_request_head(head.as_bytes(), Limits::default()).unwrap());
+
+        assert_eq!(overridden("POST /a HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: delete"), Some("DELETE".to_string()));
+        assert_eq!(overridden("POST /a HTTP/1.1\r\nHost: localhost"), None);
+        assert_eq!(overridden("GET /a HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: DELETE"), None);
+        assert_eq!(overridden("POST /a HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: TRACE"), None);
+        assert_eq!(overridden("POST /a HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: DE LETE"), None);
+    }
+
+    #[test]
+    fn normalized_host_drops_port_case_and_trailing_dot() {
+        assert_eq!(normalize_host("API.Example.com.:8080"), "api.example.com");
+        assert_eq!(normalize_host("api.example.com"), "api.example.com");
+        assert_eq!(normalize_host("[::1]:8080"), "[::1]");
+        assert_eq!(normalize_host("[::1]"), "[::1]");
+    }
+}
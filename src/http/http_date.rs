@@ -0,0 +1,50 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTH_NAMES: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// https://www.rfc-editor.org/rfc/rfc9110#section-5.6.7 - IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`. Times before the epoch
+// are clamped to it, sub-second precision is dropped.
+pub fn format(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{day_name}, {day:02} {month_name} {year} {hour:02}:{minute:02}:{second:02} GMT",
+        day_name = DAY_NAMES[(days % 7) as usize],
+        month_name = MONTH_NAMES[month as usize - 1],
+        hour = secs_of_day / 3600,
+        minute = secs_of_day % 3600 / 60,
+        second = secs_of_day % 60
+    )
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days, only for days on or after the epoch
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::format;
+
+    #[test]
+    fn formats_imf_fixdate() {
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format(UNIX_EPOCH + Duration::from_secs(784_111_777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        // leap day, sub-second part dropped
+        assert_eq!(format(UNIX_EPOCH + Duration::from_millis(1_709_164_800_999)), "Thu, 29 Feb 2024 00:00:00 GMT");
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use log::error;
 
 // https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/505
@@ -5,20 +7,29 @@ pub struct HttpStatus;
 impl HttpStatus {
     pub fn get_status_msg(code: u16) -> String {
         match code {
+            100 => "Continue".to_string(),
+            103 => "Early Hints".to_string(),
             200 => "OK".to_string(),
             201 => "Created".to_string(),
             204 => "No Content".to_string(),
             301 => "Moved Permanently".to_string(),
+            304 => "Not Modified".to_string(),
             400 => "Bad Request".to_string(),
             401 => "Unauthorized".to_string(),
             403 => "Forbidden".to_string(),
             404 => "Not Found".to_string(),
+            405 => "Method Not Allowed".to_string(),
             409 => "Conflict".to_string(),
             411 => "Length Required".to_string(),
+            413 => "Content Too Large".to_string(),
+            414 => "URI Too Long".to_string(),
             415 => "Unsupported Media Type".to_string(),
+            417 => "Expectation Failed".to_string(),
             418 => "I'm a teapot".to_string(),
+            431 => "Request Header Fields Too Large".to_string(),
             500 => "Internal Server Error".to_string(),
             503 => "Service Unavailable".to_string(),
+            504 => "Gateway Timeout".to_string(),
             505 => "HTTP Version Not Supported".to_string(),
             _ => {
                 let err_msg = format!("Status code: {code}, not found, please define it!");
@@ -28,3 +39,115 @@ impl HttpStatus {
         }
     }
 }
+
+// Plain u16s convert into it, so `Response::create(200, ...)` keeps working next to `Response::create(StatusCode::OK, ...)`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u16);
+
+impl StatusCode {
+    pub const CONTINUE: StatusCode = StatusCode(100);
+    pub const EARLY_HINTS: StatusCode = StatusCode(103);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const CONFLICT: StatusCode = StatusCode(409);
+    pub const LENGTH_REQUIRED: StatusCode = StatusCode(411);
+    pub const CONTENT_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const URI_TOO_LONG: StatusCode = StatusCode(414);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const EXPECTATION_FAILED: StatusCode = StatusCode(417);
+    pub const IM_A_TEAPOT: StatusCode = StatusCode(418);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const GATEWAY_TIMEOUT: StatusCode = StatusCode(504);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+
+    pub const fn as_u16(self) -> u16 {
+        self.0
+    }
+
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.0)
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.0)
+    }
+
+    pub fn is_redirection(self) -> bool {
+        (300..400).contains(&self.0)
+    }
+
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.0)
+    }
+
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.0)
+    }
+}
+
+impl From<u16> for StatusCode {
+    fn from(code: u16) -> Self {
+        StatusCode(code)
+    }
+}
+
+impl From<StatusCode> for u16 {
+    fn from(status: StatusCode) -> Self {
+        status.0
+    }
+}
+
+// Lets `res.status_code == 404` and `res.status_code >= 500` compare against plain numbers
+impl PartialEq<u16> for StatusCode {
+    fn eq(&self, other: &u16) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialOrd<u16> for StatusCode {
+    fn partial_cmp(&self, other: &u16) -> Option<std::cmp::Ordering> {
+        self.0.partial_cmp(other)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StatusCode;
+
+    #[test]
+    fn classifies_status_codes() {
+        assert!(StatusCode::CONTINUE.is_informational());
+        assert!(StatusCode::CREATED.is_success());
+        assert!(StatusCode::NOT_MODIFIED.is_redirection());
+        assert!(StatusCode::NOT_FOUND.is_client_error());
+        assert!(StatusCode::SERVICE_UNAVAILABLE.is_server_error());
+        assert!(!StatusCode::OK.is_client_error());
+        assert!(!StatusCode::from(600).is_server_error());
+    }
+
+    #[test]
+    fn round_trips_through_u16() {
+        assert_eq!(StatusCode::from(418), StatusCode::IM_A_TEAPOT);
+        assert_eq!(u16::from(StatusCode::NOT_FOUND), 404);
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT.as_u16(), 504);
+        assert_eq!(StatusCode::OK, 200);
+        assert!(StatusCode::INTERNAL_SERVER_ERROR >= 500);
+        assert_eq!(StatusCode::NO_CONTENT.to_string(), "204");
+    }
+}
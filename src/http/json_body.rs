@@ -0,0 +1,37 @@
+use serde_json::Value;
+
+use super::body_stream::BodyFraming;
+use super::{AsyncRequest, Error};
+
+// Checked before the handler runs, a body that fails gets a 413 or a 400 and the handler never sees it.
+// The parsed body is kept on the request, AsyncRequest::json hands it out without parsing again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JsonBodyLimit {
+    pub max_bytes: usize,
+    pub require_object: bool,
+}
+
+impl JsonBodyLimit {
+    pub(crate) async fn check(&self, req: &mut AsyncRequest) -> Result<(), Error> {
+        // a declared length already known to be too large is rejected without reading any of it
+        if let BodyFraming::Length(content_length) = req.body_framing()? {
+            if content_length > self.max_bytes {
+                return Err(Error::new(413, "JSON body too large"));
+            }
+        }
+        let mut body = Vec::new();
+        let mut stream = req.body_stream()?;
+        while let Some(piece) = stream.next().await {
+            body.extend(piece?);
+            if body.len() > self.max_bytes {
+                return Err(Error::new(413, "JSON body too large"));
+            }
+        }
+        let json = serde_json::from_slice::<Value>(&body).map_err(|e| Error::new_with_desc(400, "Body is not valid JSON", &e.to_string()))?;
+        if self.require_object && !json.is_object() {
+            return Err(Error::new(400, "JSON body must be an object"));
+        }
+        req.json = Some(json);
+        Ok(())
+    }
+}
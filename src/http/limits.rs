@@ -0,0 +1,40 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_header_count: usize,
+    pub max_header_line_length: usize,
+    // the whole head, request line and headers together, see head_size_limit
+    pub max_head_size: usize,
+    // checked before a chunk of a chunked request body gets allocated
+    pub max_chunk_size: usize,
+    // checked on its own as soon as the request target arrives, so an oversized one gets a 414 and not just the head size error
+    pub max_uri_length: usize,
+    // the whole body, Content-Length bodies are rejected before anything gets read, chunked ones once they grow past it
+    pub max_body_size: usize,
+    // not a limit on the request, how much of each body a handler reads ends up in the debug log. 0 logs nothing.
+    pub logged_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_count: 100,
+            max_header_line_length: 8190,
+            max_head_size: 8192,
+            max_chunk_size: 8 * 1024 * 1024,
+            max_uri_length: 8000,
+            max_body_size: 1024 * 1024 * 1024,
+            logged_body_bytes: 0,
+        }
+    }
+}
+
+// What the rest of a head needs next to one line at its longest, e.g. the request line next to a long header
+const HEAD_ROOM: usize = 1024;
+
+impl Limits {
+    // Never below what one line at max_uri_length or max_header_line_length needs, so raising either of those on its own is enough
+    // and is not undone by the head size
+    pub fn head_size_limit(&self) -> usize {
+        self.max_head_size.max(self.max_uri_length.max(self.max_header_line_length) + HEAD_ROOM)
+    }
+}
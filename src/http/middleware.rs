@@ -0,0 +1,53 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use super::async_handler::AsyncHandler;
+use super::response::Response;
+use super::response_builder::IntoResponse;
+use super::{AsyncRequest, Error};
+
+pub type MiddlewareFuture = Pin<Box<dyn Future<Output = Result<Response, Error>> + Send + 'static>>;
+
+// Runs around a handler: calling `next.run(req)` continues with the rest of the chain and eventually the handler, returning without
+// calling it short-circuits both, whatever the middleware returned is sent as is.
+pub trait Middleware: Send + Sync + 'static {
+    fn call(&self, req: AsyncRequest, next: Next) -> MiddlewareFuture;
+}
+
+impl<T, F, R> Middleware for T
+where
+    T: Fn(AsyncRequest, Next) -> F + Send + Sync + 'static,
+    F: Future<Output = R> + Send + 'static,
+    R: IntoResponse,
+{
+    fn call(&self, req: AsyncRequest, next: Next) -> MiddlewareFuture {
+        let future = self(req, next);
+        Box::pin(async move { future.await.into_result() })
+    }
+}
+
+// What is left of the chain for one request
+pub struct Next {
+    handler: Arc<AsyncHandler>,
+    position: usize,
+}
+
+impl Next {
+    pub(crate) fn new(handler: Arc<AsyncHandler>) -> Next {
+        Next { handler, position: 0 }
+    }
+
+    pub fn run(self, req: AsyncRequest) -> MiddlewareFuture {
+        match self.handler.middlewares.get(self.position).cloned() {
+            Some(middleware) => middleware.call(
+                req,
+                Next {
+                    handler: self.handler,
+                    position: self.position + 1,
+                },
+            ),
+            None => self.handler.func.call(req),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use super::Error;
+
+// Why a request could not be parsed, carried by the Error it gets rejected with (see Error::parse_error). Logs and tests can tell
+// the cases apart without matching on messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    RequestLineNotUtf8,
+    InvalidRequestLine,
+    MissingMethod,
+    BadMethod,
+    BadVersion,
+    TargetNotUtf8,
+    UriTooLong,
+    HeadTooLarge,
+    TooManyHeaders,
+    HeaderTooLong,
+    HeaderNotUtf8,
+    ObsoleteLineFolding,
+    MalformedHeaderLine,
+    MalformedHeaderName,
+    MissingHost,
+    UnsupportedExpectation,
+    BadContentLength,
+    ConflictingContentLength,
+    ContentLengthWithTransferEncoding,
+    BodyTooLarge,
+    BadChunkSize,
+    ChunkTooLarge,
+    UnterminatedChunk,
+    ChunkLineTooLong,
+    ChunkLineNotUtf8,
+    TooManyTrailers,
+    UnexpectedEndOfBody,
+}
+
+impl ParseError {
+    pub fn status_code(self) -> u16 {
+        match self {
+            ParseError::UriTooLong => 414,
+            ParseError::HeadTooLarge | ParseError::TooManyHeaders | ParseError::HeaderTooLong | ParseError::TooManyTrailers => 431,
+            ParseError::UnsupportedExpectation => 417,
+            ParseError::BodyTooLarge | ParseError::ChunkTooLarge => 413,
+            _ => 400,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            ParseError::RequestLineNotUtf8 => "Request line is not valid UTF-8",
+            ParseError::InvalidRequestLine => "Malformed request line",
+            ParseError::MissingMethod => "Missing request method",
+            ParseError::BadMethod => "Invalid request method",
+            ParseError::BadVersion => "Invalid HTTP version",
+            ParseError::TargetNotUtf8 => "Request target is not valid UTF-8",
+            ParseError::UriTooLong => "URI Too Long",
+            ParseError::HeadTooLarge => "Request headers too large",
+            ParseError::TooManyHeaders => "Too many headers",
+            ParseError::HeaderTooLong => "Header line too long",
+            ParseError::HeaderNotUtf8 => "Header is not valid UTF-8",
+            ParseError::ObsoleteLineFolding => "Obsolete line folding is not allowed",
+            ParseError::MalformedHeaderLine => "Malformed header line",
+            ParseError::MalformedHeaderName => "Malformed header name",
+            ParseError::MissingHost => "Missing Host header",
+            ParseError::UnsupportedExpectation => "Expectation Failed",
+            ParseError::BadContentLength => "Invalid Content-Length header",
+            ParseError::ConflictingContentLength => "Conflicting Content-Length headers",
+            ParseError::ContentLengthWithTransferEncoding => "Content-Length next to Transfer-Encoding",
+            ParseError::BodyTooLarge => "Body too large",
+            ParseError::BadChunkSize => "Invalid chunk size",
+            ParseError::ChunkTooLarge => "Chunk too large",
+            ParseError::UnterminatedChunk => "Chunk is not terminated by CRLF",
+            ParseError::ChunkLineTooLong => "Chunk line too long",
+            ParseError::ChunkLineNotUtf8 => "Chunk line is not valid UTF-8",
+            ParseError::TooManyTrailers => "Too many trailers",
+            ParseError::UnexpectedEndOfBody => "Unexpected end of body",
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(parse_error: ParseError) -> Error {
+        Error {
+            parse_error: Some(parse_error),
+            ..Error::new(parse_error.status_code(), parse_error.message())
+        }
+    }
+}
@@ -1,23 +1,294 @@
-use crate::http::http_status::HttpStatus;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use serde_json::json;
+
+use crate::http::headers::Headers;
+use crate::http::http_status::{HttpStatus, StatusCode};
 
 pub struct Response {
-    pub status_code: u16,
+    pub status_code: StatusCode,
     pub response_body: String,
+    pub headers: Headers,
+    // Sent with Transfer-Encoding: chunked instead of a Content-Length, trailers go after the last chunk
+    pub chunked: bool,
+    pub trailers: Headers,
+    // The handler frames the body itself, headers and body go out exactly as given, see raw
+    pub manual_framing: bool,
+    // The body is produced while it is being sent, see streaming
+    pub stream: Option<ResponseStream>,
+}
+
+// Pieces of a body whose length is not known up front, each goes out as its own chunk once the previous ones have been written
+#[derive(Clone)]
+pub struct ResponseStream(Arc<Mutex<dyn Iterator<Item = Result<String, String>> + Send>>);
+
+impl ResponseStream {
+    // The next piece, framed as a chunk unless the client does not accept chunked. None once the pieces run out and an error once
+    // producing one failed.
+    pub(crate) fn next_chunk(&self, chunked: bool) -> Option<Result<Vec<u8>, String>> {
+        // an empty piece would read as the last chunk, so it is skipped
+        let mut pieces = self.0.lock().unwrap();
+        loop {
+            match pieces.next()? {
+                Ok(piece) if piece.is_empty() => continue,
+                Ok(piece) if !chunked => return Some(Ok(piece.into_bytes())),
+                Ok(piece) => return Some(Ok(format!("{size:x}\r\n{piece}\r\n", size = piece.len()).into_bytes())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl PartialEq for ResponseStream {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for ResponseStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseStream")
+    }
 }
 
 impl Response {
-    pub fn create(status_code: u16, response_body: String) -> Response {
+    pub fn create(status_code: impl Into<StatusCode>, response_body: String) -> Response {
         Response {
-            status_code,
+            status_code: status_code.into(),
             response_body,
+            headers: Headers::new(),
+            chunked: false,
+            trailers: Headers::new(),
+            manual_framing: false,
+            stream: None,
+        }
+    }
+
+    // For a body of unknown length, e.g. rows read from a cursor. Sent chunked (to an HTTP/1.0 client as bare pieces, ended by closing
+    // the connection), a piece gets pulled only once the previous ones have been written, so a slow client slows down the iterator
+    // instead of the server buffering the whole body.
+    pub fn streaming(status_code: impl Into<StatusCode>, pieces: impl Iterator<Item = String> + Send + 'static) -> Response {
+        Response::try_streaming(status_code, pieces.map(Ok))
+    }
+
+    // Same as streaming, for pieces that can fail to be produced. The status line is long gone by then, so a failed piece only gets
+    // logged and the connection closed without the last chunk. The client sees a truncated body instead of one that looks complete,
+    // an HTTP/1.0 one cannot tell the difference.
+    pub fn try_streaming(status_code: impl Into<StatusCode>, pieces: impl Iterator<Item = Result<String, String>> + Send + 'static) -> Response {
+        Response {
+            chunked: true,
+            stream: Some(ResponseStream(Arc::new(Mutex::new(pieces)))),
+            ..Response::create(status_code, String::new())
         }
     }
 
+    // No Content-Length or Transfer-Encoding gets added or removed, e.g. for a handler that chunks the body itself.
+    // Getting the framing right is up to the handler, a wrong one breaks the connection for the client.
+    pub fn raw(status_code: impl Into<StatusCode>, headers: Headers, response_body: String) -> Response {
+        Response {
+            headers,
+            manual_framing: true,
+            ..Response::create(status_code, response_body)
+        }
+    }
+
+    // For a conditional GET whose cached copy is still fresh, carries no body
+    pub fn not_modified() -> Response {
+        Response::create(304, String::new())
+    }
+
+    // `{"error": {"status": 404, "message": "..."}}`
+    pub fn error_json(status_code: impl Into<StatusCode>, message: &str) -> Response {
+        let status_code = status_code.into();
+        let body = json!({ "error": { "status": status_code.as_u16(), "message": message } });
+        Response::create(status_code, body.to_string()).with_content_type("application/json")
+    }
+
+    // A minimal page titled with the status line, the message is escaped
+    pub fn error_html(status_code: impl Into<StatusCode>, message: &str) -> Response {
+        let status_code = status_code.into();
+        let title = format!("{status_code} {status_msg}", status_msg = HttpStatus::get_status_msg(status_code.as_u16()));
+        let body = format!(
+            "<!DOCTYPE html><html><head><title>{title}</title></head><body><h1>{title}</h1><p>{message}</p></body></html>",
+            message = escape_html(message)
+        );
+        Response::create(status_code, body).with_content_type("text/html; charset=utf-8")
+    }
+
+    // What the built-in error responses use: error_json or error_html, whichever the request's Accept prefers. The message goes out
+    // as plain text when it names neither.
+    pub fn error_for(request_headers: &Headers, status_code: impl Into<StatusCode>, message: &str) -> Response {
+        match request_headers.preferred_media_type(&["application/json", "text/html"]) {
+            Some("application/json") => Response::error_json(status_code, message),
+            Some(_) => Response::error_html(status_code, message),
+            None => Response::create(status_code, message.to_string()),
+        }
+    }
+
+    // Same semantics as ResponseBuilder::header
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_content_type(self, content_type: &str) -> Response {
+        self.with_header("Content-Type", content_type)
+    }
+
     pub fn get_status_line(&self) -> String {
-        let status_msg = HttpStatus::get_status_msg(self.status_code);
-        format!(
-            "HTTP/1.1 {status_code} {status_msg}",
-            status_code = self.status_code
-        )
+        let status_msg = HttpStatus::get_status_msg(self.status_code.as_u16());
+        format!("HTTP/1.1 {status_code} {status_msg}", status_code = self.status_code)
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7230#section-3.3 - 1xx, 204 and 304 responses never carry a body
+    pub fn has_body(&self) -> bool {
+        !matches!(self.status_code.as_u16(), 100..=199 | 204 | 304)
+    }
+
+    // Content-Length and Transfer-Encoding are always derived from the body, handler supplied ones are ignored (unless manual_framing).
+    // Responses that cannot have a body get neither a body (even if one was set) nor a Content-Length.
+    pub fn build_http_string(&self) -> String {
+        self.build_http_string_for(true)
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc9112#section-6.1 - a client that does not accept chunked (HTTP/1.0) gets the body that would have
+    // been chunked as it is, without trailers. Closing the connection ends it, the response has to say Connection: close.
+    pub(crate) fn build_http_string_for(&self, accepts_chunked: bool) -> String {
+        let status_line = self.get_status_line();
+        if self.manual_framing {
+            let headers = self.headers.iter().map(|(name, value)| format!("{name}: {value}\r\n")).collect::<String>();
+            return format!("{status_line}\r\n{headers}\r\n{contents}", contents = self.response_body);
+        }
+        let headers = self
+            .headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("transfer-encoding"))
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect::<String>();
+        if !self.has_body() {
+            return format!("{status_line}\r\n{headers}\r\n");
+        }
+        let contents = &self.response_body;
+        // only the head, the chunks (or the bare pieces) follow as the stream produces them
+        if self.stream.is_some() && !accepts_chunked {
+            return format!("{status_line}\r\n{headers}\r\n");
+        }
+        if self.stream.is_some() {
+            return format!("{status_line}\r\n{headers}Transfer-Encoding: chunked\r\n\r\n");
+        }
+        if self.chunked && !accepts_chunked {
+            return format!("{status_line}\r\n{headers}\r\n{contents}");
+        }
+        if self.chunked {
+            return format!("{status_line}\r\n{headers}{chunked_body}", chunked_body = self.build_chunked_body());
+        }
+        let length = contents.len();
+        format!("{status_line}\r\n{headers}Content-Length: {length}\r\n\r\n{contents}")
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7230#section-4.1 - the body goes out as a single chunk, followed by the last chunk and the trailers
+    fn build_chunked_body(&self) -> String {
+        let trailer_names = self.trailers.iter().map(|(name, _)| name).collect::<Vec<&str>>().join(", ");
+        let trailer_header = if trailer_names.is_empty() { String::new() } else { format!("Trailer: {trailer_names}\r\n") };
+        let chunk = if self.response_body.is_empty() {
+            String::new()
+        } else {
+            format!("{size:x}\r\n{contents}\r\n", size = self.response_body.len(), contents = self.response_body)
+        };
+        let trailers = self.trailers.iter().map(|(name, value)| format!("{name}: {value}\r\n")).collect::<String>();
+        format!("Transfer-Encoding: chunked\r\n{trailer_header}\r\n{chunk}0\r\n{trailers}\r\n")
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Response;
+    use crate::http::headers::Headers;
+
+    #[test]
+    fn no_content_is_serialized_without_body_and_content_length() {
+        let res = Response::create(204, "ignored".to_string());
+
+        assert_eq!(res.build_http_string(), "HTTP/1.1 204 No Content\r\n\r\n");
+    }
+
+    #[test]
+    fn with_header_calls_chain() {
+        let res = Response::create(200, "{}".to_string()).with_header("X-Request-Id", "42").with_content_type("application/json");
+
+        let http_string = res.build_http_string();
+        assert!(http_string.contains("\r\nX-Request-Id: 42\r\n"));
+        assert!(http_string.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(http_string.ends_with("Content-Length: 2\r\n\r\n{}"));
+    }
+
+    #[test]
+    fn content_length_and_chunk_size_count_utf8_bytes() {
+        // 5 chars, 4 + 1 + 2 + 1 + 4 bytes
+        let body = "🦀aé1🎉";
+        assert_eq!(body.len(), 12);
+
+        let res = Response::create(200, body.to_string());
+        assert_eq!(res.build_http_string(), "HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n🦀aé1🎉");
+
+        let mut res = Response::create(200, body.to_string());
+        res.chunked = true;
+        assert_eq!(res.build_http_string(), "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nc\r\n🦀aé1🎉\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn chunked_body_goes_out_as_is_to_a_client_without_chunked() {
+        let mut res = Response::create(200, "body".to_string());
+        res.chunked = true;
+        res.trailers.insert("X-Checksum", "abc");
+
+        assert_eq!(res.build_http_string_for(false), "HTTP/1.1 200 OK\r\n\r\nbody");
+    }
+
+    #[test]
+    fn error_bodies_in_json_and_html() {
+        let res = Response::error_json(404, "Resource: /a \"b\" not found.");
+        assert_eq!(res.headers.get("content-type"), Some("application/json"));
+        let body = serde_json::from_str::<serde_json::Value>(&res.response_body).unwrap();
+        assert_eq!(body, serde_json::json!({ "error": { "status": 404, "message": "Resource: /a \"b\" not found." } }));
+
+        let res = Response::error_html(405, "<script>");
+        assert_eq!(res.headers.get("content-type"), Some("text/html; charset=utf-8"));
+        assert_eq!(
+            res.response_body,
+            "<!DOCTYPE html><html><head><title>405 Method Not Allowed</title></head><body><h1>405 Method Not Allowed</h1><p>&lt;script&gt;</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn raw_response_keeps_the_handler_framing() {
+        let mut headers = Headers::new();
+        headers.insert("Content-Length", "100");
+
+        let res = Response::raw(200, headers, "partial".to_string());
+
+        assert_eq!(res.build_http_string(), "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\npartial");
+    }
+
+    #[test]
+    fn not_modified_keeps_headers_but_drops_body() {
+        let mut res = Response::create(304, "stale".to_string());
+        res.headers.insert("ETag", "\"abc\"");
+
+        assert_eq!(res.build_http_string(), "HTTP/1.1 304 Not Modified\r\nETag: \"abc\"\r\n\r\n");
     }
 }
@@ -0,0 +1,217 @@
+use std::time::SystemTime;
+
+use crate::http::headers::Headers;
+use crate::http::http_date;
+use crate::http::http_status::StatusCode;
+use crate::http::response::Response;
+use crate::http::Error;
+
+pub struct ResponseBuilder {
+    status_code: StatusCode,
+    headers: Headers,
+    body: String,
+    chunked: bool,
+    trailers: Headers,
+}
+
+impl ResponseBuilder {
+    pub fn new(status_code: impl Into<StatusCode>) -> ResponseBuilder {
+        ResponseBuilder {
+            status_code: status_code.into(),
+            headers: Headers::new(),
+            body: String::new(),
+            chunked: false,
+            trailers: Headers::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> ResponseBuilder {
+        self.headers.insert(name, value);
+        self
+    }
+
+    // Quoted unless it already is, a weak validator like `W/"abc"` is kept as is
+    pub fn etag(self, value: &str) -> ResponseBuilder {
+        if value.ends_with('"') && (value.starts_with('"') || value.starts_with("W/\"")) {
+            return self.header("ETag", value);
+        }
+        self.header("ETag", &format!("\"{value}\""))
+    }
+
+    pub fn last_modified(self, time: SystemTime) -> ResponseBuilder {
+        self.header("Last-Modified", &http_date::format(time))
+    }
+
+    pub fn body(mut self, body: impl Into<String>) -> ResponseBuilder {
+        self.body = body.into();
+        self
+    }
+
+    pub fn chunked(mut self) -> ResponseBuilder {
+        self.chunked = true;
+        self
+    }
+
+    // Trailers only exist in chunked responses, so adding one switches the response to chunked
+    pub fn trailer(mut self, name: &str, value: &str) -> ResponseBuilder {
+        self.trailers.insert(name, value);
+        self.chunked()
+    }
+
+    pub fn build(self) -> Response {
+        Response {
+            status_code: self.status_code,
+            response_body: self.body,
+            headers: self.headers,
+            chunked: self.chunked,
+            trailers: self.trailers,
+            manual_framing: false,
+            stream: None,
+        }
+    }
+}
+
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+
+    // What a handler's output gets turned into, only a Result can fail here and so get the connection closed
+    fn into_result(self) -> Result<Response, Error>
+    where
+        Self: Sized,
+    {
+        Ok(self.into_response())
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for ResponseBuilder {
+    fn into_response(self) -> Response {
+        self.build()
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        Response::create(self.status_code, self.title)
+    }
+}
+
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        Response::create(200, self.to_string())
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Response::create(200, self)
+    }
+}
+
+impl<R: IntoResponse, E: Into<Error>> IntoResponse for Result<R, E> {
+    fn into_response(self) -> Response {
+        self.into_result().unwrap_or_else(IntoResponse::into_response)
+    }
+
+    fn into_result(self) -> Result<Response, Error> {
+        self.map(IntoResponse::into_response).map_err(Into::into)
+    }
+}
+
+impl IntoResponse for (u16, Headers, String) {
+    fn into_response(self) -> Response {
+        let (status_code, headers, response_body) = self;
+        let mut res = Response::create(status_code, response_body);
+        res.headers = headers;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{IntoResponse, ResponseBuilder};
+    use crate::http::headers::Headers;
+    use crate::http::response::Response;
+    use crate::http::Error;
+
+    #[test]
+    fn builder_sets_status_headers_and_body() {
+        let res = ResponseBuilder::new(201).header("Location", "/users/1").body("created").build();
+
+        assert_eq!(res.build_http_string(), "HTTP/1.1 201 Created\r\nLocation: /users/1\r\nContent-Length: 7\r\n\r\ncreated");
+    }
+
+    #[test]
+    fn headers_are_written_in_insertion_order() {
+        let res = ResponseBuilder::new(200).header("X-Zulu", "1").header("X-Alpha", "2").header("X-Mike", "3").build();
+
+        assert_eq!(res.build_http_string(), "HTTP/1.1 200 OK\r\nX-Zulu: 1\r\nX-Alpha: 2\r\nX-Mike: 3\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn chunked_response_emits_declared_trailer_after_last_chunk() {
+        let res = ResponseBuilder::new(200).body("hello").trailer("X-Checksum", "abc123").build();
+
+        assert_eq!(
+            res.build_http_string(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n5\r\nhello\r\n0\r\nX-Checksum: abc123\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn etag_and_last_modified_set_their_headers() {
+        let res = ResponseBuilder::new(200).etag("abc123").last_modified(UNIX_EPOCH + Duration::from_secs(784_111_777)).build();
+        assert_eq!(res.headers.get("etag"), Some("\"abc123\""));
+        assert_eq!(res.headers.get("last-modified"), Some("Sun, 06 Nov 1994 08:49:37 GMT"));
+
+        assert_eq!(ResponseBuilder::new(200).etag("\"abc123\"").build().headers.get("etag"), Some("\"abc123\""));
+        assert_eq!(ResponseBuilder::new(200).etag("W/\"abc123\"").build().headers.get("etag"), Some("W/\"abc123\""));
+    }
+
+    #[test]
+    fn not_modified_has_no_body() {
+        let res = ResponseBuilder::new(304).etag("abc123").body("ignored").build();
+        assert_eq!(res.build_http_string(), "HTTP/1.1 304 Not Modified\r\nETag: \"abc123\"\r\n\r\n");
+
+        assert_eq!(Response::not_modified().build_http_string(), "HTTP/1.1 304 Not Modified\r\n\r\n");
+    }
+
+    #[test]
+    fn tuple_converts_into_response() {
+        let mut headers = Headers::new();
+        headers.insert("Location", "/users/1");
+
+        let res = (201, headers, "created".to_string()).into_response();
+
+        assert_eq!(res.status_code, 201);
+        assert_eq!(res.headers.get("location"), Some("/users/1"));
+        assert_eq!(res.response_body, "created");
+    }
+
+    #[test]
+    fn strings_convert_into_ok_responses() {
+        let res = "hello".into_response();
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.response_body, "hello");
+
+        assert_eq!(String::from("hello").into_response().response_body, "hello");
+    }
+
+    #[test]
+    fn err_result_keeps_the_error() {
+        let res: Result<&str, Error> = Err(Error::new(404, "gone"));
+        assert_eq!(res.into_result().err(), Some(Error::new(404, "gone")));
+
+        let res: Result<&str, Error> = Err(Error::new(404, "gone"));
+        let res = res.into_response();
+        assert_eq!(res.status_code, 404);
+        assert_eq!(res.response_body, "gone");
+    }
+}
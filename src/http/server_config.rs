@@ -0,0 +1,68 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+};
+
+use super::limits::Limits;
+
+// Server wide settings handlers can read, e.g. to build absolute links. Put into the deps by build, see AsyncRequest::server_config.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub listen_addr: String,
+    pub scheme: String,
+    // set with with_base_url, e.g. the public address behind a proxy
+    pub base_url: Option<String>,
+    pub limits: Limits,
+    // see with_method_override
+    pub method_override: bool,
+    // filled in once the server has bound its listeners, shared with the server itself
+    pub(crate) bound_addrs: Arc<OnceLock<Vec<SocketAddr>>>,
+}
+
+impl ServerConfig {
+    // The address the server actually listens on, None until it has started
+    pub fn bound_addr(&self) -> Option<SocketAddr> {
+        self.bound_addrs.get().and_then(|addrs| addrs.first().copied())
+    }
+
+    // Without a configured base URL it is made up of the scheme and the bound address (listen_addr before the server has started).
+    // Never ends with a slash, so paths can be appended as they are.
+    pub fn base_url(&self) -> String {
+        match &self.base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!(
+                "{scheme}://{addr}",
+                scheme = self.scheme,
+                addr = self.bound_addr().map_or(self.listen_addr.clone(), |addr| addr.to_string())
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, OnceLock};
+
+    use crate::http::limits::Limits;
+
+    use super::ServerConfig;
+
+    #[test]
+    fn base_url_prefers_the_configured_one_then_the_bound_address() {
+        let mut config = ServerConfig {
+            listen_addr: "0.0.0.0:0".to_string(),
+            scheme: "http".to_string(),
+            base_url: None,
+            limits: Limits::default(),
+            method_override: false,
+            bound_addrs: Arc::new(OnceLock::new()),
+        };
+        assert_eq!(config.base_url(), "http://0.0.0.0:0");
+
+        config.bound_addrs.set(vec!["127.0.0.1:8080".parse().unwrap()]).unwrap();
+        assert_eq!(config.base_url(), "http://127.0.0.1:8080");
+
+        config.base_url = Some("https://api.example.com/".to_string());
+        assert_eq!(config.base_url(), "https://api.example.com");
+    }
+}
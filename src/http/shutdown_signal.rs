@@ -0,0 +1,33 @@
+use std::{
+    io, ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Set from the signal handler, which is not allowed to do much more than this
+static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    RECEIVED.store(true, Ordering::SeqCst);
+}
+
+// SIGINT and SIGTERM stop being fatal for the whole process, they only get recorded
+pub fn install() -> io::Result<()> {
+    [libc::SIGINT, libc::SIGTERM].into_iter().try_for_each(|signal| {
+        // SAFETY: the action is fully initialized before use and the handler only touches an atomic
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, ptr::null_mut())
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    })
+}
+
+pub fn received() -> bool {
+    RECEIVED.load(Ordering::SeqCst)
+}
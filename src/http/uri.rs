@@ -0,0 +1,109 @@
+// Request target split into its components, e.g. `/a/b?x=1#top` -> path `/a/b`, query `x=1`, fragment `top`.
+// Nothing gets percent decoded, components are kept as received.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Uri {
+    path: String,
+    query: Option<String>,
+    fragment: Option<String>,
+}
+
+impl Uri {
+    pub fn parse(target: &str) -> Uri {
+        // clients never send a fragment, it is split off anyway so it cannot end up in the query or the path
+        let (rest, fragment) = match target.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment.to_string())),
+            None => (target, None),
+        };
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (rest, None),
+        };
+        Uri {
+            path: path.to_string(),
+            query,
+            fragment,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    pub fn fragment(&self) -> Option<&str> {
+        self.fragment.as_deref()
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc3986#section-2.1 - None when an escape is malformed or the result is not valid UTF-8
+    pub fn decoded_path(&self) -> Option<String> {
+        String::from_utf8(percent_decode(&self.path)?).ok()
+    }
+
+    // Pairs in the order they were sent, a pair without `=` gets an empty value
+    pub fn query_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.query
+            .as_deref()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+    }
+}
+
+fn percent_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Uri;
+
+    #[test]
+    fn splits_path_and_query() {
+        let uri = Uri::parse("/a/b?x=1&y=2");
+
+        assert_eq!(uri.path(), "/a/b");
+        assert_eq!(uri.query(), Some("x=1&y=2"));
+        assert_eq!(uri.fragment(), None);
+        assert_eq!(uri.query_pairs().collect::<Vec<_>>(), vec![("x", "1"), ("y", "2")]);
+    }
+
+    #[test]
+    fn fragment_is_split_off() {
+        let uri = Uri::parse("/a?flag&x=#top");
+
+        assert_eq!(uri.path(), "/a");
+        assert_eq!(uri.fragment(), Some("top"));
+        assert_eq!(uri.query_pairs().collect::<Vec<_>>(), vec![("flag", ""), ("x", "")]);
+    }
+
+    #[test]
+    fn plain_path_has_no_query() {
+        let uri = Uri::parse("/a/b");
+
+        assert_eq!(uri.path(), "/a/b");
+        assert_eq!(uri.query(), None);
+        assert_eq!(uri.query_pairs().count(), 0);
+    }
+
+    #[test]
+    fn decoded_path_rejects_malformed_escapes_and_invalid_utf8() {
+        assert_eq!(Uri::parse("/caf%C3%A9/a%20b?x=%zz").decoded_path().as_deref(), Some("/café/a b"));
+        assert_eq!(Uri::parse("/a%C0%80").decoded_path(), None);
+        assert_eq!(Uri::parse("/a%zz").decoded_path(), None);
+        assert_eq!(Uri::parse("/a%4").decoded_path(), None);
+    }
+}
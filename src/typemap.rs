@@ -19,7 +19,25 @@ impl DepsMap {
     }
 
     pub fn get<T: Any + Sync + Send>(&self) -> Option<&T> {
-        self.map.get(&TypeId::of::<T>()).unwrap().downcast_ref::<T>()
+        self.map.get(&TypeId::of::<T>()).and_then(|dep| dep.downcast_ref::<T>())
+    }
+
+    // Shares the stored dep itself, e.g. to hand it to a spawned task that outlives the request
+    pub fn get_arc<T: Any + Sync + Send>(&self) -> Option<Arc<T>> {
+        self.map.get(&TypeId::of::<T>()).cloned().and_then(|dep| dep.downcast::<T>().ok())
+    }
+
+    pub fn contains<T: Any + Sync + Send>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub(crate) fn contains_type_id(&self, type_id: TypeId) -> bool {
+        self.map.contains_key(&type_id)
+    }
+
+    // Hands the dep back when nothing else shares it (see get_arc), otherwise it only stops being reachable from this map
+    pub fn remove<T: Any + Sync + Send>(&mut self) -> Option<T> {
+        self.map.remove(&TypeId::of::<T>()).and_then(|dep| dep.downcast::<T>().ok()).and_then(|dep| Arc::try_unwrap(dep).ok())
     }
 }
 
@@ -31,6 +49,8 @@ impl Default for DepsMap {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+
     use super::DepsMap;
 
     #[test]
@@ -40,4 +60,35 @@ mod tests {
 
         assert_eq!(*type_map.get::<String>().unwrap(), "a string".to_string());
     }
+
+    #[test]
+    fn missing_dep_is_none() {
+        let type_map = DepsMap::new();
+
+        assert!(type_map.get::<String>().is_none());
+        assert!(type_map.get_arc::<String>().is_none());
+        assert!(!type_map.contains::<String>());
+    }
+
+    #[test]
+    fn get_arc_shares_the_stored_dep() {
+        let mut type_map = DepsMap::new();
+        type_map.insert(Arc::new(Mutex::new(vec![1])));
+
+        let shared = type_map.get_arc::<Arc<Mutex<Vec<i32>>>>().unwrap();
+        shared.lock().unwrap().push(2);
+
+        assert_eq!(*type_map.get::<Arc<Mutex<Vec<i32>>>>().unwrap().lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn remove_takes_the_dep_out() {
+        let mut type_map = DepsMap::new();
+        type_map.insert(42u32);
+
+        assert!(type_map.contains::<u32>());
+        assert_eq!(type_map.remove::<u32>(), Some(42));
+        assert!(!type_map.contains::<u32>());
+        assert_eq!(type_map.remove::<u32>(), None);
+    }
 }
@@ -24,3 +24,865 @@ fn get_works() {
     let resp: Value = serde_json::from_str(resp.as_str()).unwrap();
     assert_eq!(resp["status"], "ok");
 }
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_server_serves_status_on_an_ephemeral_port() {
+    use serde_json::Value;
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+    assert_ne!(server.port(), 0);
+
+    let resp = reqwest::blocking::get(server.url("/status")).unwrap().text().unwrap();
+    let resp: Value = serde_json::from_str(resp.as_str()).unwrap();
+    assert_eq!(resp["status"], "ok");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn multiple_acceptors_handle_a_burst_of_connections() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use serde_json::Value;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::common::{self, TestServer};
+
+    let handlers = HashSet::from([common::get_status_handler()]);
+    let server = Arc::new(TestServer::start_with(AsyncHttpServer::builder().with_acceptors(2).with_handlers(handlers)));
+
+    let clients = (0..64)
+        .map(|_| {
+            let server = server.clone();
+            thread::spawn(move || reqwest::blocking::get(server.url("/status")).unwrap().text().unwrap())
+        })
+        .collect::<Vec<_>>();
+
+    clients.into_iter().for_each(|client| {
+        let resp: Value = serde_json::from_str(client.join().unwrap().as_str()).unwrap();
+        assert_eq!(resp["status"], "ok");
+    });
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn route_added_after_start_becomes_reachable() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    async fn plugin_handler(_: AsyncRequest) -> Result<Response, String> {
+        Ok(Response::create(200, "plugin".to_string()))
+    }
+
+    let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+    assert_eq!(reqwest::blocking::get(server.url("/plugin")).unwrap().status(), 404);
+
+    server.server().add_route(AsyncHandler::new("GET", "/plugin", plugin_handler));
+
+    let resp = reqwest::blocking::get(server.url("/plugin")).unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().unwrap(), "plugin");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn scoped_handlers_resolve_under_the_prefix() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    async fn user_handler(req: AsyncRequest) -> Result<Response, String> {
+        Ok(Response::create(200, format!("{tid}/{id}", tid = req.path_params["tid"], id = req.path_params["id"])))
+    }
+
+    let server = TestServer::start_with(
+        AsyncHttpServer::builder()
+            .with_scope("/api/v1", HashSet::from([common::get_status_handler()]))
+            .with_scope("/tenants/:tid/", HashSet::from([AsyncHandler::new("GET", "/users/:id", user_handler)])),
+    );
+
+    assert_eq!(reqwest::blocking::get(server.url("/api/v1/status")).unwrap().status(), 200);
+    assert_eq!(reqwest::blocking::get(server.url("/status")).unwrap().status(), 404);
+    assert_eq!(reqwest::blocking::get(server.url("/tenants/acme/users/7")).unwrap().text().unwrap(), "acme/7");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn vhost_handlers_only_serve_their_host() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+
+    use crate::common::TestServer;
+
+    async fn api_handler(_: AsyncRequest) -> &'static str {
+        "api"
+    }
+
+    let server = TestServer::start_with(AsyncHttpServer::builder().with_vhost("api.example.com", HashSet::from([AsyncHandler::new("GET", "/users", api_handler)])));
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /users HTTP/1.1\r\nHost: api.example.com\r\n\r\n");
+    let res = client.read_response();
+    assert_eq!(res.status_code(), 200);
+    assert_eq!(res.body, b"api");
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /users HTTP/1.1\r\nHost: www.example.com\r\n\r\n");
+    assert_eq!(client.read_response().status_code(), 404);
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /users HTTP/1.1\r\n\r\n");
+    assert_eq!(client.read_response().status_code(), 400);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn empty_router_answers_404_and_cleans_up() {
+    use std::collections::HashSet;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::common::TestServer;
+
+    let server = TestServer::start(HashSet::new());
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /anything HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let res = client.read_response();
+    assert_eq!(res.status_line, "HTTP/1.1 404 Not Found");
+    assert_eq!(res.header("content-length"), Some("30"));
+    assert_eq!(res.body, b"Resource: /anything not found.");
+    assert_eq!(res.header("connection"), None);
+    // kept alive, the next request goes over the same connection
+    client.send_raw(b"GET /else HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(client.read_response().body, b"Resource: /else not found.");
+    drop(client);
+
+    // the body gets read before closing, an unread one would reset the connection instead
+    let mut client = server.raw_client();
+    let upload = vec![b'x'; 32 * 1024];
+    client.send_raw(format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {len}\r\n\r\n", len = upload.len()).as_bytes());
+    client.send_raw(&upload);
+    assert_eq!(client.read_response().status_code(), 404);
+    assert!(client.is_closed());
+
+    while !server.server().connections.lock().unwrap().is_empty() {
+        sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn built_in_client_errors_keep_the_connection_alive() {
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+
+    let mut client = server.raw_client();
+    client.send_raw(b"TRACE /status HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let res = client.read_response();
+    assert_eq!(res.status_code(), 405);
+    assert_eq!(res.header("connection"), None);
+    client.send_raw(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(client.read_response().status_code(), 200);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn raised_limits_admit_heads_over_8_kib() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    let builder = || AsyncHttpServer::builder().with_handlers(HashSet::from([common::get_status_handler()]));
+    let padding = "x".repeat(12 * 1024);
+    let many_headers = (0..12).map(|i| format!("X-Pad-{i}: {pad}\r\n", pad = &padding[..1024])).collect::<String>();
+    let cases = [
+        (builder().with_max_uri_length(16 * 1024), format!("GET /status?pad={padding} HTTP/1.1\r\nHost: localhost\r\n\r\n")),
+        (
+            builder().with_max_header_line_length(16 * 1024),
+            format!("GET /status HTTP/1.1\r\nHost: localhost\r\nX-Pad: {padding}\r\n\r\n"),
+        ),
+        (builder().with_max_head_size(32 * 1024), format!("GET /status HTTP/1.1\r\nHost: localhost\r\n{many_headers}\r\n")),
+    ];
+
+    for (builder, request) in cases {
+        let server = TestServer::start_with(builder);
+        let mut client = server.raw_client();
+        client.send_raw(request.as_bytes());
+        assert_eq!(client.read_response().status_code(), 200);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn middleware_can_short_circuit_with_its_own_response() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::middleware::Next;
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::{AsyncRequest, Error};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::common::TestServer;
+
+    static PAST_AUTH: AtomicUsize = AtomicUsize::new(0);
+
+    async fn auth(req: AsyncRequest, next: Next) -> Result<Response, Error> {
+        if req.headers.get("authorization") != Some("Bearer let-me-in") {
+            return Ok(Response::create(401, "who are you?".to_string()).with_header("WWW-Authenticate", "Bearer realm=\"api\""));
+        }
+        next.run(req).await
+    }
+
+    async fn count(req: AsyncRequest, next: Next) -> Result<Response, Error> {
+        PAST_AUTH.fetch_add(1, Ordering::SeqCst);
+        next.run(req).await
+    }
+
+    async fn secret(_: AsyncRequest) -> &'static str {
+        "secret"
+    }
+
+    let server = TestServer::start_with(
+        AsyncHttpServer::builder()
+            .with_middleware(auth)
+            .with_handlers(HashSet::from([AsyncHandler::new("GET", "/secret", secret).with_middleware(count)])),
+    );
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /secret HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let res = client.read_response();
+    assert_eq!(res.status_line, "HTTP/1.1 401 Unauthorized");
+    assert_eq!(res.header("www-authenticate"), Some("Bearer realm=\"api\""));
+    assert_eq!(res.body, b"who are you?");
+    assert_eq!(PAST_AUTH.load(Ordering::SeqCst), 0);
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /secret HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer let-me-in\r\n\r\n");
+    let res = client.read_response();
+    assert_eq!(res.status_code(), 200);
+    assert_eq!(res.body, b"secret");
+    assert_eq!(PAST_AUTH.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn shared_mutable_dep_is_visible_across_handlers() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use crate::common::TestServer;
+
+    type Cache = Arc<Mutex<Vec<i32>>>;
+
+    async fn push_handler(req: AsyncRequest) -> Result<Response, String> {
+        let cache = req.deps.get_arc::<Cache>().ok_or("no cache")?;
+        cache.lock().unwrap().push(req.path_params["value"].parse().map_err(|_| "not a number")?);
+        Ok(Response::create(201, String::new()))
+    }
+
+    async fn list_handler(req: AsyncRequest) -> Result<Response, String> {
+        let cache = req.deps.get::<Cache>().ok_or("no cache")?;
+        Ok(Response::create(200, format!("{:?}", cache.lock().unwrap())))
+    }
+
+    let handlers = HashSet::from([AsyncHandler::new("POST", "/cache/:value", push_handler), AsyncHandler::new("GET", "/cache", list_handler)]);
+    let server = TestServer::start_with(AsyncHttpServer::builder().with_handlers(handlers).with_dep(Cache::default()));
+
+    // kept alive, the pooled connection gets reused for the later requests
+    let client = reqwest::blocking::Client::new();
+    assert_eq!(client.post(server.url("/cache/1")).send().unwrap().status(), 201);
+    assert_eq!(client.post(server.url("/cache/2")).send().unwrap().status(), 201);
+    assert_eq!(client.get(server.url("/cache")).send().unwrap().text().unwrap(), "[1, 2]");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn pipelined_requests_get_responses_in_order() {
+    use serde_json::Value;
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\nGET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+    let status = client.read_response();
+    assert_eq!(status.status_line, "HTTP/1.1 200 OK");
+    let status: Value = serde_json::from_slice(&status.body).unwrap();
+    assert_eq!(status["status"], "ok");
+
+    let missing = client.read_response();
+    assert_eq!(missing.status_line, "HTTP/1.1 404 Not Found");
+    assert_eq!(missing.body, b"Resource: /missing not found.");
+    // a request sent only after the responses arrived still gets answered on the same connection
+    client.send_raw(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let closing = client.read_response();
+    assert_eq!(closing.status_line, "HTTP/1.1 200 OK");
+    assert_eq!(closing.header("connection"), Some("close"));
+    assert!(client.is_closed());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn pipelined_burst_runs_one_handler_at_a_time() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use crate::common::TestServer;
+
+    // (in flight, most in flight at once)
+    let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+    let slow_handler = AsyncHandler::from_fn_with_state("GET", "/slow/:n", counts.clone(), |counts, req| async move {
+        let in_flight = counts.0.fetch_add(1, Ordering::SeqCst) + 1;
+        counts.1.fetch_max(in_flight, Ordering::SeqCst);
+        // blocks the worker on purpose, the other workers are free to pick up the next request if it got dispatched
+        sleep(Duration::from_millis(20));
+        counts.0.fetch_sub(1, Ordering::SeqCst);
+        req.path_params["n"].clone()
+    });
+    let server = TestServer::start_with(AsyncHttpServer::builder().with_custom_num_workers(4).with_handlers(HashSet::from([slow_handler])));
+
+    let mut client = server.raw_client();
+    let burst = (1..=8).map(|n| format!("GET /slow/{n} HTTP/1.1\r\nHost: localhost\r\n\r\n")).collect::<String>();
+    client.send_raw(burst.as_bytes());
+
+    let bodies = (1..=8).map(|_| String::from_utf8(client.read_response().body).unwrap()).collect::<Vec<_>>();
+    assert_eq!(bodies, (1..=8).map(|n| n.to_string()).collect::<Vec<_>>());
+    assert_eq!(counts.1.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn handler_error_closes_the_connection() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use crate::common::TestServer;
+
+    async fn failing_handler(_: AsyncRequest) -> Result<Response, String> {
+        Err("database is gone".to_string())
+    }
+
+    let server = TestServer::start(HashSet::from([AsyncHandler::new("GET", "/users", failing_handler)]));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+    stream.write_all(b"GET /users HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    // only returns once the server has closed its end
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(response.contains("\r\nConnection: close\r\n"));
+    assert!(server.server().connections.lock().unwrap().is_empty());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn health_and_readiness_endpoints_answer_once_started() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use serde_json::Value;
+
+    use crate::common::TestServer;
+
+    let server = TestServer::start_with(AsyncHttpServer::builder().with_health_endpoint("/healthz").with_readiness_endpoint("/readyz"));
+
+    for path in ["/healthz", "/readyz"] {
+        let resp = reqwest::blocking::get(server.url(path)).unwrap();
+        assert_eq!(resp.status(), 200);
+        let resp: Value = serde_json::from_str(resp.text().unwrap().as_str()).unwrap();
+        assert_eq!(resp["status"], "ok");
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn graceful_close_delivers_the_response_to_a_slow_reader() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::common::TestServer;
+
+    // answers without reading the upload, it is still unread when the connection gets closed
+    async fn ignoring_handler(_: AsyncRequest) -> Result<Response, String> {
+        Ok(Response::create(200, "x".repeat(64 * 1024)))
+    }
+
+    let handlers = HashSet::from([AsyncHandler::new("POST", "/upload", ignoring_handler)]);
+    let server = TestServer::start_with(AsyncHttpServer::builder().with_handlers(handlers).with_graceful_close(Duration::from_secs(5)));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+    let upload = vec![b'u'; 64 * 1024];
+    stream
+        .write_all(format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {len}\r\n\r\n", len = upload.len()).as_bytes())
+        .unwrap();
+    stream.write_all(&upload).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with(&[b'x'; 64 * 1024]));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn idle_connections_get_swept() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    use crate::common::{self, TestServer};
+
+    let server = TestServer::start_with(
+        AsyncHttpServer::builder()
+            .with_handlers(HashSet::from([common::get_status_handler()]))
+            .with_idle_timeout(Duration::from_millis(300)),
+    );
+
+    // never finishes its request
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /status HTTP/1.1\r\n");
+    let sent = Instant::now();
+
+    // the read times out long before a connection that never gets swept would close
+    assert!(client.is_closed());
+    assert!(sent.elapsed() >= Duration::from_millis(300));
+    assert!(server.server().connections.lock().unwrap().is_empty());
+    // still serving everyone else
+    assert_eq!(reqwest::blocking::get(server.url("/status")).unwrap().status(), 200);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn port_already_in_use_is_reported_to_the_caller() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::io;
+    use std::net::TcpListener;
+
+    use crate::common::TestServer;
+
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    // a running server of our own, the same user starting a second instance must not end up sharing its port
+    let running = TestServer::start_with(AsyncHttpServer::builder().with_acceptors(2));
+
+    for addr in [taken.local_addr().unwrap().to_string(), format!("127.0.0.1:{port}", port = running.port())] {
+        let server = AsyncHttpServer::builder().with_addr(&addr).with_custom_num_workers(1).build();
+
+        let err = server.try_start_blocking().unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(err.to_string().contains(&addr));
+        assert!(server.local_addr().is_none());
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn port_out_of_range_is_reported_to_the_caller() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::io;
+
+    let main = AsyncHttpServer::builder().with_addr("127.0.0.1:70000").with_custom_num_workers(1).build();
+    let additional = AsyncHttpServer::builder()
+        .with_addr("127.0.0.1:0")
+        .with_additional_addr("127.0.0.1:70000")
+        .with_custom_num_workers(1)
+        .build();
+
+    for server in [main, additional] {
+        let err = server.try_start_blocking().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("127.0.0.1:70000"));
+        assert!(server.local_addr().is_none());
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn awaited_ready_server_reports_a_failed_start() {
+    use nvo_servers::futures::workers::Workers;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::io;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = Arc::new(AsyncHttpServer::builder().with_addr(&taken.local_addr().unwrap().to_string()).with_custom_num_workers(1).build());
+
+    let workers = Workers::new(1);
+    let ready_server = server.clone();
+    let ready = workers.queue_with_result(async move { ready_server.ready().await }).unwrap();
+    let start_err = server.try_start_blocking().unwrap_err();
+    let ready_err = ready.get().unwrap_err();
+    workers.poison_all();
+
+    assert_eq!(ready_err.kind(), io::ErrorKind::AddrInUse);
+    assert_eq!(ready_err.to_string(), start_err.to_string());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn additional_addresses_serve_the_same_routes() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    let server = TestServer::start_with(
+        AsyncHttpServer::builder()
+            .with_acceptors(2)
+            .with_additional_addr("127.0.0.1:0")
+            .with_handlers(HashSet::from([common::get_status_handler()])),
+    );
+
+    let addrs = server.server().local_addrs().to_vec();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0].port(), addrs[1].port());
+    for addr in addrs {
+        let resp = reqwest::blocking::get(format!("http://{addr}/status")).unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn connection_close_gets_one_response() {
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    let res = client.read_response();
+
+    assert_eq!(res.status_code(), 200);
+    assert_eq!(res.header("connection"), Some("close"));
+    assert!(client.is_closed());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn streaming_response_is_reassembled_by_the_client() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+
+    use crate::common::TestServer;
+
+    async fn numbers_handler(_: AsyncRequest) -> Response {
+        Response::streaming(200, (1..=1000).map(|n| format!("{n}\n")))
+    }
+    let server = TestServer::start(HashSet::from([AsyncHandler::new("GET", "/numbers", numbers_handler)]));
+
+    let mut client = server.raw_client();
+    client.send_raw(b"GET /numbers HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    let res = client.read_response();
+
+    assert_eq!(res.header("transfer-encoding"), Some("chunked"));
+    assert_eq!(res.header("content-length"), None);
+    assert_eq!(String::from_utf8(res.body).unwrap(), (1..=1000).map(|n| format!("{n}\n")).collect::<String>());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn aborted_connection_gets_dropped() {
+    use socket2::Socket;
+    use std::collections::HashSet;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    use crate::common::{self, TestServer};
+
+    let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+
+    let mut client = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+    client.write_all(b"GET /status HTTP/1.1\r\n").unwrap();
+    let started = Instant::now();
+    while server.server().connections.lock().unwrap().is_empty() {
+        assert!(started.elapsed() < Duration::from_secs(5), "connection never got registered");
+        sleep(Duration::from_millis(1));
+    }
+    // a zero linger close sends a reset instead of a FIN
+    let client = Socket::from(client);
+    client.set_linger(Some(Duration::ZERO)).unwrap();
+    drop(client);
+
+    let aborted = Instant::now();
+    while !server.server().connections.lock().unwrap().is_empty() {
+        assert!(aborted.elapsed() < Duration::from_secs(5), "aborted connection was not dropped");
+        sleep(Duration::from_millis(1));
+    }
+    // still serving
+    assert_eq!(reqwest::blocking::get(server.url("/status")).unwrap().status(), 200);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn handler_builds_location_from_the_configured_base_url() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::response::Response;
+    use nvo_servers::http::AsyncRequest;
+    use std::collections::HashSet;
+
+    use crate::common::TestServer;
+
+    async fn create_handler(req: AsyncRequest) -> Response {
+        let base_url = req.server_config().unwrap().base_url();
+        Response::create(201, String::new()).with_header("Location", &format!("{base_url}/items/1"))
+    }
+    let handlers = || HashSet::from([AsyncHandler::new("POST", "/items", create_handler)]);
+    let behind_proxy = TestServer::start_with(AsyncHttpServer::builder().with_handlers(handlers()).with_base_url("https://api.example.com/"));
+    let direct = TestServer::start(handlers());
+
+    let created = |server: &TestServer| {
+        let mut client = server.raw_client();
+        client.send_raw(b"POST /items HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n");
+        let res = client.read_response();
+        assert_eq!(res.status_code(), 201);
+        res.header("location").unwrap().to_string()
+    };
+    assert_eq!(created(&behind_proxy), "https://api.example.com/items/1");
+    assert_eq!(created(&direct), format!("http://127.0.0.1:{port}/items/1", port = direct.port()));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn swapped_deps_reach_new_requests_only() {
+    use nvo_servers::http::async_handler::AsyncHandler;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::AsyncRequest;
+    use nvo_servers::typemap::DepsMap;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use crate::common::TestServer;
+
+    struct ApiKey(&'static str);
+
+    async fn key_handler(req: AsyncRequest) -> String {
+        req.deps.get::<ApiKey>().unwrap().0.to_string()
+    }
+    // holds on to its request until the test has swapped the deps
+    let in_flight = Arc::new(Barrier::new(2));
+    let slow_handler = AsyncHandler::from_fn_with_state("GET", "/slow", in_flight.clone(), |in_flight, req| async move {
+        in_flight.wait();
+        in_flight.wait();
+        req.deps.get::<ApiKey>().unwrap().0.to_string()
+    });
+    let handlers = HashSet::from([AsyncHandler::new("GET", "/key", key_handler), slow_handler]);
+    let server = Arc::new(TestServer::start_with(
+        AsyncHttpServer::builder().with_custom_num_workers(4).with_handlers(handlers).with_dep(ApiKey("old")),
+    ));
+
+    let slow_server = server.clone();
+    let slow = thread::spawn(move || reqwest::blocking::get(slow_server.url("/slow")).unwrap().text().unwrap());
+    in_flight.wait();
+    let mut deps = DepsMap::new();
+    deps.insert(ApiKey("new"));
+    server.server().update_deps(deps);
+
+    assert_eq!(reqwest::blocking::get(server.url("/key")).unwrap().text().unwrap(), "new");
+    in_flight.wait();
+    assert_eq!(slow.join().unwrap(), "old");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn awaited_ready_server_serves_right_away() {
+    use nvo_servers::futures::workers::Workers;
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::collections::HashSet;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::common;
+
+    let server = Arc::new(AsyncHttpServer::builder().with_addr("127.0.0.1:0").with_handlers(HashSet::from([common::get_status_handler()])).build());
+    let server_clj = server.clone();
+    let server_thread = thread::spawn(move || server_clj.start_blocking());
+
+    let workers = Workers::new(1);
+    let ready_server = server.clone();
+    workers.queue_with_result(async move { ready_server.ready().await }).unwrap().get().unwrap();
+    workers.poison_all();
+
+    let port = server.local_addr().unwrap().port();
+    assert_eq!(reqwest::blocking::get(format!("http://127.0.0.1:{port}/status")).unwrap().status(), 200);
+    server.shutdown_requested.store(true, Ordering::SeqCst);
+    server_thread.join().unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn routes_registered_by_method_get_served() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::AsyncRequest;
+
+    use crate::common::TestServer;
+
+    async fn list_handler(_: AsyncRequest) -> &'static str {
+        "listed"
+    }
+    async fn create_handler(mut req: AsyncRequest) -> Result<String, nvo_servers::http::Error> {
+        Ok(format!("created {body}", body = req.body().await?))
+    }
+    let server = TestServer::start_with(AsyncHttpServer::builder().get("/items", list_handler).post("/items", create_handler));
+
+    assert_eq!(reqwest::blocking::get(server.url("/items")).unwrap().text().unwrap(), "listed");
+    let created = reqwest::blocking::Client::new().post(server.url("/items")).body("x").send().unwrap().text().unwrap();
+    assert_eq!(created, "created x");
+    assert_eq!(reqwest::blocking::Client::new().delete(server.url("/items")).send().unwrap().status(), 404);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn method_override_routes_a_post_as_the_method_it_names() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use nvo_servers::http::AsyncRequest;
+
+    use crate::common::TestServer;
+
+    async fn delete_handler(req: AsyncRequest) -> String {
+        format!("{method} handled", method = req.method())
+    }
+    async fn create_handler(_: AsyncRequest) -> &'static str {
+        "created"
+    }
+    let overriding = TestServer::start_with(AsyncHttpServer::builder().delete("/items", delete_handler).post("/items", create_handler).with_method_override(true));
+    let plain = TestServer::start_with(AsyncHttpServer::builder().delete("/items", delete_handler).post("/items", create_handler));
+
+    let post_as_delete = |server: &TestServer| {
+        let res = reqwest::blocking::Client::new().post(server.url("/items")).header("X-HTTP-Method-Override", "DELETE").send().unwrap();
+        res.text().unwrap()
+    };
+    assert_eq!(post_as_delete(&overriding), "DELETE handled");
+    assert_eq!(post_as_delete(&plain), "created");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn rejected_and_abandoned_connections_leave_no_fd_behind() {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::common::{self, RawClient, TestServer};
+
+    // Sockets of this process accepted on `port`, i.e. the server's end of its connections. The listener itself does not count.
+    fn open_connection_fds(port: u16) -> usize {
+        let tcp = fs::read_to_string("/proc/net/tcp").unwrap();
+        let inodes = tcp
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields = line.split_whitespace().collect::<Vec<&str>>();
+                let local_port = u16::from_str_radix(fields[1].rsplit_once(':')?.1, 16).ok()?;
+                // 0A is LISTEN
+                (local_port == port && fields[3] != "0A").then(|| fields[9].to_string())
+            })
+            .collect::<HashSet<String>>();
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+            .filter(|link| {
+                link.to_str()
+                    .and_then(|link| link.strip_prefix("socket:[")?.strip_suffix(']'))
+                    .is_some_and(|inode| inodes.contains(inode))
+            })
+            .count()
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+    let port = server.port();
+    assert_eq!(open_connection_fds(port), 0);
+    // the count does see the server's end of a connection
+    let held = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    wait_until(|| open_connection_fds(port) == 1);
+    assert_eq!(open_connection_fds(port), 1);
+    drop(held);
+
+    for _ in 0..20 {
+        let mut rejected = RawClient::connect(port);
+        rejected.send_raw(b"GARBAGE\r\n\r\n");
+        assert_eq!(rejected.read_response().status_code(), 400);
+        assert!(rejected.is_closed());
+
+        // gone halfway through the head, the server reads EOF
+        let mut abandoned = RawClient::connect(port);
+        abandoned.send_raw(b"GET /status HTTP/1.1\r\nHo");
+        drop(abandoned);
+
+        drop(TcpStream::connect(("127.0.0.1", port)).unwrap());
+    }
+
+    wait_until(|| open_connection_fds(port) == 0 && server.server().connections.lock().unwrap().is_empty());
+    let left_in_the_map = server
+        .server()
+        .connections
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, (_, state, _))| format!("{fd}: {state}", fd = *id as i32))
+        .collect::<Vec<_>>();
+    assert_eq!(open_connection_fds(port), 0, "still in the map: {left_in_the_map:?}");
+    assert!(left_in_the_map.is_empty());
+}
@@ -0,0 +1,32 @@
+mod common;
+
+#[cfg(all(unix, any(feature = "poll_reactor", not(any(target_os = "linux", target_os = "freebsd", target_os = "macos")))))]
+mod async_poll_tests {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+
+    use serde_json::Value;
+    use std::collections::HashSet;
+
+    use crate::common::{self, TestServer};
+
+    #[test]
+    fn get_works() {
+        let server = TestServer::start(HashSet::from([common::get_status_handler()]));
+
+        let resp = reqwest::blocking::get(server.url("/status")).unwrap().text().unwrap();
+        let resp: Value = serde_json::from_str(resp.as_str()).unwrap();
+        assert_eq!(resp["status"], "ok");
+    }
+
+    #[test]
+    fn acceptors_share_the_connections() {
+        let handlers = HashSet::from([common::get_status_handler()]);
+        let server = TestServer::start_with(AsyncHttpServer::builder().with_acceptors(2).with_handlers(handlers));
+
+        for _ in 0..16 {
+            let resp = reqwest::blocking::get(server.url("/status")).unwrap().text().unwrap();
+            let resp: Value = serde_json::from_str(resp.as_str()).unwrap();
+            assert_eq!(resp["status"], "ok");
+        }
+    }
+}
@@ -1,12 +1,17 @@
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use nvo_servers::http::async_http_server::AsyncHttpServer;
-use nvo_servers::http::response::Response;
-use serde_json::json;
+use nvo_servers::futures::workers::Workers;
 use nvo_servers::http::async_handler::AsyncHandler;
+use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerBuilder, AsyncHttpServerTrt};
+use nvo_servers::http::response::Response;
 use nvo_servers::http::AsyncRequest;
+use serde_json::json;
 
 #[allow(dead_code)]
 pub fn get_status_handler() -> AsyncHandler {
@@ -19,7 +24,172 @@ pub fn get_status_handler() -> AsyncHandler {
 
 #[allow(dead_code)]
 pub fn wait_for_server_to_start(server: Arc<AsyncHttpServer>) {
-    while !server.started.load(std::sync::atomic::Ordering::SeqCst) {
-        thread::sleep(Duration::from_millis(10));
+    let workers = Workers::new(1);
+    workers
+        .queue_with_result(async move { server.ready().await })
+        .expect("Could not queue the wait")
+        .get()
+        .expect("Server failed to start");
+    workers.poison_all();
+}
+
+// Server listening on an ephemeral port for the duration of a test, shut down when dropped
+#[allow(dead_code)]
+pub struct TestServer {
+    server: Option<Arc<AsyncHttpServer>>,
+    server_thread: Option<JoinHandle<()>>,
+    port: u16,
+}
+
+#[allow(dead_code)]
+impl TestServer {
+    pub fn start(handlers: HashSet<AsyncHandler>) -> TestServer {
+        TestServer::start_with(AsyncHttpServer::builder().with_handlers(handlers))
+    }
+
+    pub fn start_with(builder: AsyncHttpServerBuilder) -> TestServer {
+        let server = Arc::new(builder.with_addr("127.0.0.1:0").build());
+        let server_clj = server.clone();
+        let server_thread = thread::spawn(move || server_clj.start_blocking());
+
+        wait_for_server_to_start(server.clone());
+        let port = server.local_addr().expect("Started server has no local address").port();
+
+        TestServer {
+            server: Some(server),
+            server_thread: Some(server_thread),
+            port,
+        }
+    }
+
+    pub fn server(&self) -> &AsyncHttpServer {
+        self.server.as_ref().unwrap()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{port}{path}", port = self.port)
+    }
+
+    pub fn raw_client(&self) -> RawClient {
+        RawClient::connect(self.port)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let server = self.server.take().unwrap();
+        server.shutdown_requested.store(true, Ordering::SeqCst);
+        if let Some(server_thread) = self.server_thread.take() {
+            let _ = server_thread.join();
+        }
+        // the server thread is gone, so this is the last reference
+        if let Ok(server) = Arc::try_unwrap(server) {
+            server.shutdown_gracefully();
+        }
+    }
+}
+
+// Talks HTTP over a plain TcpStream, for asserting keep-alive, pipelining and chunked bodies byte for byte
+#[allow(dead_code)]
+pub struct RawClient {
+    reader: BufReader<TcpStream>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct RawResponse {
+    pub status_line: String,
+    // names lowercased, in the order they were received
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl RawResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == &name.to_lowercase()).map(|(_, value)| value.as_str())
+    }
+
+    pub fn status_code(&self) -> u16 {
+        self.status_line.split(' ').nth(1).and_then(|code| code.parse().ok()).expect("Malformed status line")
+    }
+}
+
+#[allow(dead_code)]
+impl RawClient {
+    pub fn connect(port: u16) -> RawClient {
+        let stream = TcpStream::connect(("127.0.0.1", port)).expect("Could not connect to the test server");
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        RawClient { reader: BufReader::new(stream) }
+    }
+
+    // A second handle on the same connection, e.g. to keep sending from another thread while this one reads the responses
+    pub fn writer(&self) -> TcpStream {
+        self.reader.get_ref().try_clone().expect("Could not clone the connection")
+    }
+
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.reader.get_mut().write_all(bytes).expect("Could not send to the test server");
+    }
+
+    // Reads exactly one response, whatever follows it stays buffered for the next call
+    pub fn read_response(&mut self) -> RawResponse {
+        let status_line = self.read_line();
+        let mut headers = Vec::new();
+        loop {
+            let line = self.read_line();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').expect("Malformed header line");
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+        let mut response = RawResponse {
+            status_line,
+            headers,
+            body: Vec::new(),
+        };
+        let status_code = response.status_code();
+        if (100..200).contains(&status_code) || status_code == 204 || status_code == 304 {
+            return response;
+        }
+        if response.header("transfer-encoding").is_some_and(|te| te.contains("chunked")) {
+            response.body = self.read_chunked_body();
+        } else if let Some(length) = response.header("content-length") {
+            response.body = vec![0; length.parse().expect("Malformed Content-Length")];
+            self.reader.read_exact(&mut response.body).expect("Response body cut short");
+        } else {
+            self.reader.read_to_end(&mut response.body).expect("Could not read response body");
+        }
+        response
+    }
+
+    // True once the server has closed its end, without anything left to read
+    pub fn is_closed(&mut self) -> bool {
+        matches!(self.reader.fill_buf(), Ok([]))
+    }
+
+    fn read_chunked_body(&mut self) -> Vec<u8> {
+        let mut body = Vec::new();
+        loop {
+            let size = usize::from_str_radix(self.read_line().split(';').next().unwrap().trim(), 16).expect("Malformed chunk size");
+            if size == 0 {
+                while !self.read_line().is_empty() {}
+                return body;
+            }
+            let mut chunk = vec![0; size + 2];
+            self.reader.read_exact(&mut chunk).expect("Chunk cut short");
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    fn read_line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).expect("Could not read from the test server");
+        line.trim_end_matches(['\r', '\n']).to_string()
     }
 }
@@ -0,0 +1,31 @@
+mod common;
+
+// Own test binary, the signal goes to the whole process
+#[test]
+#[cfg(target_os = "linux")]
+fn sigterm_stops_a_server_with_signal_handler_installed() {
+    use nvo_servers::http::async_http_server::{AsyncHttpServer, AsyncHttpServerTrt};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::common;
+
+    let handlers = HashSet::from([common::get_status_handler()]);
+    let server = Arc::new(AsyncHttpServer::builder().with_addr("127.0.0.1:0").with_handlers(handlers).build());
+    server.install_signal_handler().unwrap();
+    let server_clj = server.clone();
+    let server_thread = thread::spawn(move || server_clj.start_blocking());
+    common::wait_for_server_to_start(server.clone());
+
+    unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !server_thread.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(server_thread.is_finished());
+    server_thread.join().unwrap();
+    Arc::into_inner(server).unwrap().shutdown_gracefully();
+}